@@ -0,0 +1,194 @@
+use std::{convert::Infallible, future};
+
+use futures_util::{SinkExt, Stream, StreamExt, stream};
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast::error::RecvError;
+use warp::{Filter, Rejection, Reply, http::StatusCode, sse, ws::{Message, WebSocket, Ws}};
+
+use crate::{DeviceId, status::{StatusEvent, StatusLevel, StatusManager}};
+
+/// Error response body.
+#[derive(Serialize)]
+struct ApiError {
+    /// Error message.
+    error: String,
+}
+
+/// Event stream query parameters.
+#[derive(Clone, Deserialize, Debug)]
+struct EventQuery {
+    /// Only stream events for this device.
+    device: Option<DeviceId>,
+
+    /// Only stream events of this level or higher.
+    level: Option<StatusLevel>,
+
+    /// Resume after this event ID, for clients that can't set the
+    /// `Last-Event-ID` header.
+    last_event_id: Option<u64>,
+}
+
+impl EventQuery {
+    /// Returns true if an event passes the query's filters.
+    fn matches(&self, event: &StatusEvent) -> bool {
+        self.device.is_none_or(|device| device == event.device_id)
+            && self.level.is_none_or(|level| event.status.level >= level)
+    }
+}
+
+/// JSON status API routes, under `/api/v1`.
+/// 
+/// - `GET /api/v1/devices`: every device with its latest status.
+/// - `GET /api/v1/devices/{id}`: a device with its latest status.
+/// - `GET /api/v1/devices/{id}/history`: a device's status history,
+///   oldest first.
+/// - `GET /api/v1/events`: status updates as Server-Sent Events.
+/// - `GET /api/v1/ws`: status updates as WebSocket text messages.
+/// 
+/// Timestamps are RFC 3339 in UTC, a device's `message`, `level` and
+/// `timestamp` are null until its first status update.
+/// 
+/// The event streams take optional `device` and minimum `level` query
+/// parameters, and resume after the event ID in the `Last-Event-ID` header
+/// or `last_event_id` query parameter. Subscribers that fall too far
+/// behind are disconnected and should resume from the last event seen.
+pub fn routes(status_manager: StatusManager) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let with_status_manager = warp::any().map(move || status_manager.clone());
+
+    let devices = warp::path!("api" / "v1" / "devices")
+        .and(warp::get())
+        .and(with_status_manager.clone())
+        .and_then(devices);
+    let device = warp::path!("api" / "v1" / "devices" / DeviceId)
+        .and(warp::get())
+        .and(with_status_manager.clone())
+        .and_then(device);
+    let history = warp::path!("api" / "v1" / "devices" / DeviceId / "history")
+        .and(warp::get())
+        .and(with_status_manager.clone())
+        .and_then(history);
+    let events = warp::path!("api" / "v1" / "events")
+        .and(warp::get())
+        .and(warp::query::<EventQuery>())
+        .and(warp::header::optional::<u64>("last-event-id"))
+        .and(with_status_manager.clone())
+        .and_then(events);
+    let ws = warp::path!("api" / "v1" / "ws")
+        .and(warp::ws())
+        .and(warp::query::<EventQuery>())
+        .and(with_status_manager)
+        .map(|ws: Ws, query: EventQuery, status_manager: StatusManager| {
+            ws.on_upgrade(move |socket| ws_events(socket, query, status_manager))
+        });
+
+    devices.or(device).or(history).or(events).or(ws)
+}
+
+/// List every device.
+async fn devices(status_manager: StatusManager) -> Result<warp::reply::Response, Infallible> {
+    Ok(warp::reply::json(&status_manager.devices().await).into_response())
+}
+
+/// Get a device.
+async fn device(device_id: DeviceId, status_manager: StatusManager) -> Result<warp::reply::Response, Infallible> {
+    match status_manager.device(device_id).await {
+        Some(device) => Ok(warp::reply::json(&device).into_response()),
+        None => Ok(not_found(device_id)),
+    }
+}
+
+/// Get a device's status history.
+async fn history(device_id: DeviceId, status_manager: StatusManager) -> Result<warp::reply::Response, Infallible> {
+    match status_manager.device_history(device_id).await {
+        Some(history) => Ok(warp::reply::json(&history).into_response()),
+        None => Ok(not_found(device_id)),
+    }
+}
+
+/// Stream status events as Server-Sent Events.
+async fn events(query: EventQuery, last_event_id: Option<u64>, status_manager: StatusManager) -> Result<warp::reply::Response, Infallible> {
+    let last_event_id = last_event_id.or(query.last_event_id);
+    let events = match event_stream(&status_manager, query, last_event_id).await {
+        Some(events) => events,
+        None => return Ok(shutting_down()),
+    };
+
+    let events = events.map(|event| sse::Event::default().id(event.status.id.to_string()).json_data(&event));
+    Ok(sse::reply(sse::keep_alive().stream(events)).into_response())
+}
+
+/// Stream status events to a WebSocket until either side closes it.
+async fn ws_events(socket: WebSocket, query: EventQuery, status_manager: StatusManager) {
+    let (mut sender, mut receiver) = socket.split();
+    let last_event_id = query.last_event_id;
+    let events = match event_stream(&status_manager, query, last_event_id).await {
+        Some(events) => events,
+        None => {
+            let _ = sender.close().await;
+            return;
+        },
+    };
+    tokio::pin!(events);
+
+    loop {
+        tokio::select! {
+            event = events.next() => {
+                let event = match event {
+                    Some(event) => event,
+                    None => break,
+                };
+                let text = serde_json::to_string(&event).expect("status events must serialize");
+                if sender.send(Message::text(text)).await.is_err() {
+                    return;
+                }
+            },
+
+            // Messages from the client are ignored, the stream ends when it closes.
+            message = receiver.next() => {
+                match message {
+                    Some(Ok(message)) if !message.is_close() => {},
+                    _ => return,
+                }
+            },
+        }
+    }
+
+    let _ = sender.close().await;
+}
+
+/// Subscribe to status events after `last_event_id` that match a query.
+/// Returns None if the status manager is shut down.
+async fn event_stream(
+    status_manager: &StatusManager,
+    query: EventQuery,
+    last_event_id: Option<u64>)
+ -> Option<impl Stream<Item = StatusEvent>>
+{
+    let (missed, receiver) = status_manager.subscribe(last_event_id).await?;
+
+    // End the stream if the subscriber lags, so it resumes from its last event.
+    let live = stream::unfold(receiver, |mut receiver| async move {
+        match receiver.recv().await {
+            Ok(event) => Some((event, receiver)),
+            Err(RecvError::Lagged(skipped)) => {
+                log::warn!("Event stream subscriber missed {} events, disconnecting", skipped);
+                None
+            },
+            Err(RecvError::Closed) => None,
+        }
+    });
+
+    Some(stream::iter(missed).chain(live).filter(move |event| future::ready(query.matches(event))))
+}
+
+/// Response for event streams requested during shutdown.
+fn shutting_down() -> warp::reply::Response {
+    let error = ApiError { error: "Cerberus is shutting down".to_string() };
+    warp::reply::with_status(warp::reply::json(&error), StatusCode::SERVICE_UNAVAILABLE).into_response()
+}
+
+/// Response for an unknown device.
+fn not_found(device_id: DeviceId) -> warp::reply::Response {
+    let error = ApiError { error: format!("Unknown device {}", device_id) };
+    warp::reply::with_status(warp::reply::json(&error), StatusCode::NOT_FOUND).into_response()
+}
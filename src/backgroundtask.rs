@@ -1,4 +1,4 @@
-use std::{future::Future, mem::replace};
+use std::future::Future;
 
 use tokio::task::JoinHandle;
 use tokio_util::sync::{CancellationToken, DropGuard};
@@ -49,11 +49,11 @@ impl<T: Send + 'static> BackgroundTask<T> {
     /// Shutdown the task (if still running), wait for completion, and return the result.
     pub async fn finish(&mut self) -> Result<T, anyhow::Error> {
         self.shutdown_token.cancel();
-        let shutdown_dropguard = replace(&mut self.shutdown_dropguard, None);
+        let shutdown_dropguard = self.shutdown_dropguard.take();
         if let Some(shutdown_dropguard) = shutdown_dropguard {
             shutdown_dropguard.disarm();
         }
-        let join_handle = replace(&mut self.join_handle, None);
+        let join_handle = self.join_handle.take();
         if let Some(join_handle) = join_handle {
             Ok(join_handle.await?)
         } else {
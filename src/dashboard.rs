@@ -0,0 +1,15 @@
+use warp::{Filter, Rejection, Reply};
+
+/// Dashboard page, with its styles and scripts embedded.
+const DASHBOARD_HTML: &str = include_str!("dashboard.html");
+
+/// HTML dashboard route, served at `/`.
+/// 
+/// The dashboard shows a card for each device with its current state and
+/// history, and a banner while any device is in alarm. It loads devices
+/// from the JSON API and follows the event stream for updates.
+pub fn route() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path::end()
+        .and(warp::get())
+        .map(|| warp::reply::html(DASHBOARD_HTML))
+}
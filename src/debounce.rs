@@ -0,0 +1,209 @@
+use std::{collections::VecDeque, time::Duration};
+
+use serde::{Serialize, Deserialize};
+use tokio::time::Instant;
+
+use crate::notification::Notification;
+
+/// Status debounce and flap detection configuration for a device.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct DebounceConfig {
+    /// Seconds a status has to stay unchanged before it is notified.
+    /// 
+    /// Only applies to `Info` and `Status` updates, warnings and alarms
+    /// are always notified immediately.
+    #[serde(default)]
+    pub debounce: u64,
+
+    /// Number of state changes within `flap_window` that raise a flapping
+    /// warning, flap detection is disabled if not set.
+    pub flap_threshold: Option<usize>,
+
+    /// Flap detection window in seconds.
+    #[serde(default = "DebounceConfig::default_flap_window")]
+    pub flap_window: u64,
+}
+
+impl DebounceConfig {
+    /// Default flap detection window.
+    fn default_flap_window() -> u64 {
+        5 * 60
+    }
+}
+
+/// Debounce state for a device.
+pub struct Debouncer {
+    /// Debounce configuration.
+    config: DebounceConfig,
+
+    /// Notification waiting for the status to settle, and the generation
+    /// it was held with.
+    pending: Option<(u64, Notification)>,
+
+    /// Incremented whenever a notification is held, so a stale release
+    /// timer doesn't send a newer notification early.
+    generation: u64,
+
+    /// Last status message notified for the device.
+    last_notified: Option<String>,
+
+    /// Times of recent state changes.
+    changes: VecDeque<Instant>,
+
+    /// Whether a flapping warning has been raised and the device hasn't
+    /// been stable for a whole flap window since.
+    flapping: bool,
+}
+
+impl Debouncer {
+    /// Create a new debouncer.
+    pub fn new(config: DebounceConfig) -> Self {
+        Self {
+            config,
+            pending: None,
+            generation: 0,
+            last_notified: None,
+            changes: VecDeque::new(),
+            flapping: false,
+        }
+    }
+
+    /// Time a status has to stay unchanged before it is notified.
+    pub fn delay(&self) -> Duration {
+        Duration::from_secs(self.config.debounce)
+    }
+
+    /// Flap detection window in seconds.
+    pub fn flap_window(&self) -> u64 {
+        self.config.flap_window
+    }
+
+    /// Record a state change, returning the number of changes in the flap
+    /// window if the device just started flapping.
+    pub fn record_change(&mut self) -> Option<usize> {
+        let threshold = self.config.flap_threshold?;
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.flap_window);
+        while self.changes.front().is_some_and(|change| now.duration_since(*change) > window) {
+            self.changes.pop_front();
+        }
+
+        // A full window without changes ends the flapping.
+        if self.changes.is_empty() {
+            self.flapping = false;
+        }
+        self.changes.push_back(now);
+
+        if self.changes.len() > threshold && !self.flapping {
+            self.flapping = true;
+            return Some(self.changes.len());
+        }
+        None
+    }
+
+    /// Hold a notification until the status settles, returning the
+    /// generation to release it with. Returns None if the status is
+    /// already held or was already notified.
+    pub fn hold(&mut self, notification: Notification) -> Option<u64> {
+        let current = match &self.pending {
+            Some((_, pending)) => Some(&pending.message),
+            None => self.last_notified.as_ref(),
+        };
+        if current == Some(&notification.message) {
+            return None;
+        }
+
+        self.generation += 1;
+        self.pending = Some((self.generation, notification));
+        Some(self.generation)
+    }
+
+    /// Record a notification that was sent without being held.
+    pub fn notified(&mut self, notification: &Notification) {
+        self.pending = None;
+        self.last_notified = Some(notification.message.clone());
+    }
+
+    /// Release the held notification once the status has settled. Returns
+    /// None if a newer notification replaced it or the settled status was
+    /// already notified.
+    pub fn release(&mut self, generation: u64) -> Option<Notification> {
+        match &self.pending {
+            Some((pending_generation, _)) if *pending_generation == generation => {},
+            _ => return None,
+        }
+        self.flush()
+    }
+
+    /// Take the held notification, if any, regardless of how long the
+    /// status has been stable.
+    pub fn flush(&mut self) -> Option<Notification> {
+        let (_, notification) = self.pending.take()?;
+        if self.last_notified.as_ref() == Some(&notification.message) {
+            return None;
+        }
+        self.last_notified = Some(notification.message.clone());
+        Some(notification)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::StatusLevel;
+
+    /// Debouncer with flap detection after `flap_threshold` changes.
+    fn debouncer(flap_threshold: Option<usize>) -> Debouncer {
+        Debouncer::new(DebounceConfig { debounce: 30, flap_threshold, flap_window: 60 })
+    }
+
+    /// Status notification with a message.
+    fn status(message: &str) -> Notification {
+        Notification::system(message, StatusLevel::Status)
+    }
+
+    #[test]
+    fn release_sends_only_the_latest_held_status() {
+        let mut debouncer = debouncer(None);
+        let first = debouncer.hold(status("open")).unwrap();
+        let second = debouncer.hold(status("closed")).unwrap();
+
+        assert!(debouncer.release(first).is_none());
+        assert_eq!(debouncer.release(second).unwrap().message, "closed");
+        assert!(debouncer.release(second).is_none());
+    }
+
+    #[test]
+    fn hold_ignores_repeated_statuses() {
+        let mut debouncer = debouncer(None);
+        let generation = debouncer.hold(status("open")).unwrap();
+        assert!(debouncer.hold(status("open")).is_none());
+        assert_eq!(debouncer.release(generation).unwrap().message, "open");
+
+        // The status was notified, repeating it doesn't hold it again.
+        assert!(debouncer.hold(status("open")).is_none());
+    }
+
+    #[test]
+    fn settling_back_to_the_notified_status_sends_nothing() {
+        let mut debouncer = debouncer(None);
+        debouncer.notified(&status("closed"));
+        debouncer.hold(status("open")).unwrap();
+        let generation = debouncer.hold(status("closed")).unwrap();
+
+        assert!(debouncer.release(generation).is_none());
+        assert!(debouncer.flush().is_none());
+    }
+
+    #[test]
+    fn record_change_warns_once_per_flapping_period() {
+        let mut flapping = debouncer(Some(2));
+        assert_eq!(flapping.record_change(), None);
+        assert_eq!(flapping.record_change(), None);
+        assert_eq!(flapping.record_change(), Some(3));
+        assert_eq!(flapping.record_change(), None);
+
+        // Flap detection is disabled without a threshold.
+        assert_eq!(debouncer(None).record_change(), None);
+    }
+}
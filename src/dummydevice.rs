@@ -11,13 +11,11 @@ pub struct DummyDeviceMonitor {
 }
 
 impl DummyDeviceMonitor {
-    pub fn new(status_manger: StatusManager, states: Vec<(String, bool)>, period: u64) -> anyhow::Result<Self> {
-        if states.len() == 0 {
+    pub fn new(id: DeviceId, status_manger: StatusManager, states: Vec<(String, bool)>, period: u64) -> anyhow::Result<Self> {
+        if states.is_empty() {
             anyhow::bail!("dummy device must have at least one state");
         }
 
-        let id = DeviceId::default();
-
         let task = BackgroundTask::spawn(|shutdown_token| {
             async move {
                 status_manger.update_status(id, "Dummy device monitor started.", StatusLevel::Info).await;
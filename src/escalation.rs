@@ -0,0 +1,287 @@
+use std::{sync::{Arc, Mutex}, time::Duration};
+
+use serde::{Serialize, Deserialize};
+use tokio::{sync::Notify, time::Instant};
+
+use crate::{DeviceId, backgroundtask::BackgroundTask, notification::{Notification, NotificationManager, NotificationTargets}, status::{StatusLevel, StatusManager}};
+
+/// Alarm escalation configuration.
+/// 
+/// Alarms are sent to the alarm notification targets first (tier 1), then
+/// to each escalation tier in turn until the alarm is acknowledged.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct EscalationConfig {
+    /// Base URL of the status server used in acknowledgement links, e.g.
+    /// `http://cerberus.local:8080`.
+    pub ack_base_url: String,
+
+    /// Escalation tiers after the alarm notification targets, starting
+    /// with tier 2.
+    pub tiers: Vec<EscalationTier>,
+}
+
+/// Alarm escalation tier.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct EscalationTier {
+    /// Minutes to wait for an acknowledgement after the previous tier was
+    /// notified before notifying this tier.
+    pub delay: u64,
+
+    /// Notification targets for this tier.
+    pub targets: NotificationTargets,
+}
+
+/// Unacknowledged alarm.
+#[derive(Clone)]
+pub struct PendingAlarm {
+    /// Acknowledgement token.
+    pub token: String,
+
+    /// Device the alarm is for.
+    pub device_id: DeviceId,
+
+    /// Alarm notification.
+    pub notification: Notification,
+
+    /// Index of the next escalation tier to notify.
+    next_tier: usize,
+
+    /// Time to notify the next tier, None once every tier was notified or
+    /// if the tier's delay is too long to schedule.
+    next_escalation: Option<Instant>,
+}
+
+/// Alarm escalator, escalates alarms that are not acknowledged in time.
+/// 
+/// Each device has at most one pending alarm, further alarms from the
+/// device are acknowledged with the first. Pending alarms are kept in
+/// memory and are forgotten if Cerberus is restarted.
+pub struct AlarmEscalator {
+    /// Escalation configuration.
+    config: EscalationConfig,
+
+    /// Unacknowledged alarms.
+    pending: Mutex<Vec<PendingAlarm>>,
+
+    /// Wakes the escalation task when an alarm is added.
+    alarm_added: Notify,
+
+    /// Escalation background task.
+    task: tokio::sync::Mutex<Option<BackgroundTask<()>>>,
+}
+
+impl AlarmEscalator {
+    /// Create a new alarm escalator and start its background task.
+    /// 
+    /// Escalations are queued with `notification_manager`, so they are
+    /// retried and spooled like any other alarm.
+    pub fn start(config: EscalationConfig, status_manager: StatusManager, notification_manager: NotificationManager) -> Arc<Self> {
+        let escalator = Arc::new(Self {
+            config,
+            pending: Default::default(),
+            alarm_added: Notify::new(),
+            task: Default::default(),
+        });
+
+        let task_escalator = escalator.clone();
+        let task = BackgroundTask::spawn(|shutdown_token| async move {
+            task_escalator.run(status_manager, notification_manager, shutdown_token).await
+        });
+        *escalator.task.try_lock().expect("escalator task must be unlocked") = Some(task);
+
+        escalator
+    }
+
+    /// Acknowledgement link for a token.
+    pub fn ack_url(&self, token: &str) -> String {
+        format!("{}/ack/{}", self.config.ack_base_url.trim_end_matches('/'), token)
+    }
+
+    /// Get the acknowledgement token for a device's next alarm, the token
+    /// of its pending alarm if it has one or a new token otherwise.
+    pub fn alarm_token(&self, device_id: DeviceId) -> String {
+        let pending = self.pending.lock().expect("escalator lock poisoned");
+        match pending.iter().find(|alarm| alarm.device_id == device_id) {
+            Some(alarm) => alarm.token.clone(),
+            None => format!("{:032x}", rand::random::<u128>()),
+        }
+    }
+
+    /// Track an alarm for escalation with a token from `alarm_token()`,
+    /// unless the device already has a pending alarm.
+    pub fn add_alarm(&self, device_id: DeviceId, token: String, notification: &Notification) {
+        let mut pending = self.pending.lock().expect("escalator lock poisoned");
+        if pending.iter().any(|alarm| alarm.device_id == device_id) {
+            return;
+        }
+
+        pending.push(PendingAlarm {
+            token,
+            device_id,
+            notification: notification.clone(),
+            next_tier: 0,
+            next_escalation: self.config.tiers.first().and_then(|tier| Instant::now().checked_add(Self::tier_delay(tier))),
+        });
+        self.alarm_added.notify_one();
+    }
+
+    /// Get the unacknowledged alarm for a token, if any.
+    pub fn pending_alarm(&self, token: &str) -> Option<PendingAlarm> {
+        let pending = self.pending.lock().expect("escalator lock poisoned");
+        pending.iter().find(|alarm| alarm.token == token).cloned()
+    }
+
+    /// Acknowledge an alarm, stopping its escalation. Returns the alarm,
+    /// or None if the token is unknown or was already acknowledged.
+    pub fn acknowledge(&self, token: &str) -> Option<PendingAlarm> {
+        let mut pending = self.pending.lock().expect("escalator lock poisoned");
+        let index = pending.iter().position(|alarm| alarm.token == token)?;
+        Some(pending.remove(index))
+    }
+
+    /// Stop the escalation background task.
+    pub async fn shutdown(&self) {
+        if let Some(mut task) = self.task.lock().await.take() {
+            let _ = task.finish().await;
+        }
+    }
+
+    /// Delay before notifying a tier.
+    fn tier_delay(tier: &EscalationTier) -> Duration {
+        Duration::from_secs(tier.delay.saturating_mul(60))
+    }
+
+    /// Run the escalation background task.
+    async fn run(
+        &self,
+        status_manager: StatusManager,
+        notification_manager: NotificationManager,
+        shutdown_token: tokio_util::sync::CancellationToken)
+    {
+        loop {
+            let next_escalation = self.pending.lock().expect("escalator lock poisoned").iter()
+                .filter_map(|alarm| alarm.next_escalation)
+                .min();
+            let wait = async move {
+                match next_escalation {
+                    Some(next_escalation) => tokio::time::sleep_until(next_escalation).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                _ = wait => {},
+                _ = self.alarm_added.notified() => continue,
+                _ = shutdown_token.cancelled() => break,
+            }
+
+            for (alarm, tier_index) in self.take_due() {
+                let tier = &self.config.tiers[tier_index];
+                let tier_number = tier_index + 2;
+                let mut escalation = alarm.notification.clone();
+                escalation.text = format!("Unacknowledged alarm escalated to tier {}: {}", tier_number, escalation.text);
+                escalation.message = format!("Escalated to tier {}: {}", tier_number, escalation.message);
+
+                notification_manager.send_alarm_to(tier.targets.clone(), escalation);
+                status_manager.log(format!("Alarm '{}' not acknowledged, escalated to tier {}.", alarm.notification.message, tier_number), StatusLevel::Warning).await;
+            }
+        }
+    }
+
+    /// Advance every alarm that is due for escalation to its next tier,
+    /// returning the alarms and the index of the tier to notify.
+    fn take_due(&self) -> Vec<(PendingAlarm, usize)> {
+        let now = Instant::now();
+        let mut due = vec![];
+        let mut pending = self.pending.lock().expect("escalator lock poisoned");
+        for alarm in pending.iter_mut() {
+            if alarm.next_escalation.is_none_or(|next_escalation| next_escalation > now) {
+                continue;
+            }
+
+            due.push((alarm.clone(), alarm.next_tier));
+            alarm.next_tier += 1;
+
+            // After the last tier the alarm is kept so it can still be acknowledged.
+            alarm.next_escalation = self.config.tiers.get(alarm.next_tier)
+                .and_then(|tier| now.checked_add(Self::tier_delay(tier)));
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Alarm escalator with tiers with the given delays, without its
+    /// background task.
+    fn escalator(delays: &[u64]) -> AlarmEscalator {
+        let tiers = delays.iter()
+            .map(|delay| serde_json::from_value(serde_json::json!({
+                "delay": delay,
+                "targets": { "DiscordWebhook": { "url": "https://example.com/tier", "username": null } },
+            })).unwrap())
+            .collect();
+        AlarmEscalator {
+            config: EscalationConfig { ack_base_url: "http://cerberus.local/".to_string(), tiers },
+            pending: Default::default(),
+            alarm_added: Notify::new(),
+            task: Default::default(),
+        }
+    }
+
+    /// Tier indices returned by `take_due()`.
+    fn due_tiers(escalator: &AlarmEscalator) -> Vec<usize> {
+        escalator.take_due().into_iter().map(|(_, tier)| tier).collect()
+    }
+
+    #[test]
+    fn take_due_advances_through_each_tier_once() {
+        let escalator = escalator(&[0, 0]);
+        let device_id = DeviceId::default();
+        let token = escalator.alarm_token(device_id);
+        escalator.add_alarm(device_id, token.clone(), &Notification::system("Intruder", StatusLevel::Alarm));
+
+        assert_eq!(due_tiers(&escalator), [0]);
+        assert_eq!(due_tiers(&escalator), [1]);
+        assert!(due_tiers(&escalator).is_empty());
+
+        // The alarm can still be acknowledged after the last tier.
+        assert!(escalator.pending_alarm(&token).is_some());
+        assert_eq!(escalator.alarm_token(device_id), token);
+    }
+
+    #[test]
+    fn take_due_waits_for_tier_delay() {
+        let escalator = escalator(&[5, u64::MAX]);
+        let device_id = DeviceId::default();
+        escalator.add_alarm(device_id, escalator.alarm_token(device_id), &Notification::system("Intruder", StatusLevel::Alarm));
+        assert!(due_tiers(&escalator).is_empty());
+
+        // Delays too long to schedule never escalate.
+        escalator.pending.lock().unwrap()[0].next_escalation = Some(Instant::now());
+        assert_eq!(due_tiers(&escalator), [0]);
+        assert!(escalator.pending.lock().unwrap()[0].next_escalation.is_none());
+    }
+
+    #[test]
+    fn acknowledge_stops_escalation() {
+        let escalator = escalator(&[0]);
+        let device_id = DeviceId::default();
+        let token = escalator.alarm_token(device_id);
+        escalator.add_alarm(device_id, token.clone(), &Notification::system("Intruder", StatusLevel::Alarm));
+
+        // Further alarms from the device are acknowledged with the first.
+        escalator.add_alarm(device_id, escalator.alarm_token(device_id), &Notification::system("Door", StatusLevel::Alarm));
+        assert_eq!(escalator.pending.lock().unwrap().len(), 1);
+
+        let alarm = escalator.acknowledge(&token).unwrap();
+        assert_eq!(alarm.notification.message, "Intruder");
+        assert!(escalator.acknowledge(&token).is_none());
+        assert!(escalator.pending_alarm(&token).is_none());
+        assert!(due_tiers(&escalator).is_empty());
+        assert_ne!(escalator.alarm_token(device_id), token);
+        assert_eq!(escalator.ack_url(&token), format!("http://cerberus.local/ack/{}", token));
+    }
+}
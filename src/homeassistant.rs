@@ -0,0 +1,172 @@
+use std::sync::Mutex;
+
+use rumqttc::{AsyncClient, QoS};
+use serde::Serialize;
+
+use crate::{DeviceId, DeviceKind};
+
+/// Alarm panel state, as reported to Home Assistant's
+/// `alarm_control_panel` entity.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum PanelState {
+    /// Panel is disarmed.
+    Disarmed,
+    /// Panel is counting down to arm.
+    Arming,
+    /// Panel is armed.
+    ArmedAway,
+    /// Panel is counting down to alarm, waiting to be disarmed.
+    Pending,
+    /// Panel is in alarm.
+    Triggered,
+}
+
+/// Device announced to Home Assistant.
+#[derive(Clone, Debug)]
+pub struct AnnouncedDevice {
+    /// Device monitor ID, used in the device's MQTT topics.
+    pub id: DeviceId,
+
+    /// Kind of device.
+    pub kind: DeviceKind,
+
+    /// Configured device name.
+    pub name: String,
+
+    /// Stable identifier derived from the device configuration, used
+    /// for the Home Assistant unique IDs.
+    pub key: String,
+}
+
+/// Home Assistant MQTT discovery, announces device monitors as Home
+/// Assistant entities.
+/// 
+/// Each device gets a sensor with the latest status message and a
+/// binary sensor that is on while the device is in alarm. Alarm panels
+/// also get an `alarm_control_panel` entity. Entities are read-only,
+/// Cerberus ignores commands sent from Home Assistant.
+pub struct HomeAssistantDiscovery {
+    /// Discovery topic prefix, usually `homeassistant`.
+    discovery_prefix: String,
+
+    /// Cerberus topic prefix.
+    topic_prefix: String,
+
+    /// Announced devices, re-announced whenever the broker connection or
+    /// Home Assistant restarts.
+    devices: Mutex<Vec<AnnouncedDevice>>,
+}
+
+impl HomeAssistantDiscovery {
+    /// Create a new Home Assistant discovery announcer.
+    pub fn new(discovery_prefix: String, topic_prefix: String) -> Self {
+        Self {
+            discovery_prefix,
+            topic_prefix,
+            devices: Default::default(),
+        }
+    }
+
+    /// Home Assistant status topic, Home Assistant publishes `online`
+    /// here when it starts.
+    pub fn status_topic(&self) -> String {
+        format!("{}/status", self.discovery_prefix)
+    }
+
+    /// Topic for a device's alarm panel state.
+    pub fn panel_topic(&self, device_id: DeviceId) -> String {
+        format!("{}/devices/{}/panel", self.topic_prefix, device_id)
+    }
+
+    /// Announce a device and remember it for re-announcement.
+    pub fn add_device(&self, client: &AsyncClient, device: AnnouncedDevice) {
+        self.announce_device(client, &device);
+        self.devices.lock().expect("discovery lock poisoned").push(device);
+    }
+
+    /// Announce all devices.
+    pub fn announce_all(&self, client: &AsyncClient) {
+        let devices = self.devices.lock().expect("discovery lock poisoned").clone();
+        for device in &devices {
+            self.announce_device(client, device);
+        }
+    }
+
+    /// Home Assistant node ID for a device key, with any characters
+    /// other than ASCII letters and digits replaced by underscores.
+    fn node_id(key: &str) -> String {
+        let key: String = key.chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+            .collect();
+        format!("cerberus_{}", key.trim_matches('_'))
+    }
+
+    /// Publish the discovery configuration for a device's entities.
+    fn announce_device(&self, client: &AsyncClient, announced: &AnnouncedDevice) {
+        let device_id = announced.id;
+        let kind = announced.kind;
+        let node_id = Self::node_id(&announced.key);
+        let state_topic = format!("{}/devices/{}/state", self.topic_prefix, device_id);
+        let device = serde_json::json!({
+            "identifiers": [node_id],
+            "name": announced.name,
+            "manufacturer": kind.manufacturer(),
+            "model": kind.to_string(),
+        });
+        let availability_topic = format!("{}/status", self.topic_prefix);
+
+        let mut entities = vec![
+            ("sensor", "status", serde_json::json!({
+                "name": "Status",
+                "unique_id": format!("{}_status", node_id),
+                "state_topic": state_topic,
+                "value_template": "{{ value_json.message }}",
+                "json_attributes_topic": state_topic,
+                "availability_topic": availability_topic,
+                "device": device,
+            })),
+            ("binary_sensor", "alarm", serde_json::json!({
+                "name": "Alarm",
+                "unique_id": format!("{}_alarm", node_id),
+                "device_class": "safety",
+                "state_topic": state_topic,
+                "value_template": "{{ 'ON' if value_json.level in ['Alarm', 'Critical'] else 'OFF' }}",
+                "availability_topic": availability_topic,
+                "device": device,
+            })),
+        ];
+
+        if kind == DeviceKind::NapcoGemini {
+            entities.push(("alarm_control_panel", "panel", serde_json::json!({
+                "name": "Alarm Panel",
+                "unique_id": format!("{}_panel", node_id),
+                "state_topic": self.panel_topic(device_id),
+                "command_topic": format!("{}/devices/{}/panel/set", self.topic_prefix, device_id),
+                "code_arm_required": false,
+                "supported_features": [],
+                "availability_topic": availability_topic,
+                "device": device,
+            })));
+        }
+
+        for (component, object_id, config) in entities {
+            let topic = format!("{}/{}/{}/{}/config", self.discovery_prefix, component, node_id, object_id);
+            if let Err(err) = client.try_publish(topic, QoS::AtLeastOnce, true, config.to_string()) {
+                log::error!("Failed to publish Home Assistant discovery config: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_id_is_derived_from_device_key() {
+        assert_eq!(HomeAssistantDiscovery::node_id("Front Door"), "cerberus_front_door");
+        assert_eq!(HomeAssistantDiscovery::node_id("/dev/ttyUSB0"), "cerberus_dev_ttyusb0");
+        assert_eq!(HomeAssistantDiscovery::node_id("dummy_1"), "cerberus_dummy_1");
+    }
+}
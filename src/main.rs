@@ -1,26 +1,48 @@
+use std::fmt::Display;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
 use async_trait::async_trait;
 use lazy_static::lazy_static;
+use regex::Regex;
 use serde::{Serialize, Deserialize};
 
+use crate::mqtt::{MqttConfig, MqttPublisher};
 use crate::napcogemini::NapcoGeminiDeviceMonitor;
-use crate::notification::{NotificationTarget, NotificationManager};
+use crate::notification::{Notification, NotificationTarget, NotificationTargets, NotificationManager};
+use crate::quiethours::QuietHoursConfig;
+use crate::ratelimit::RateLimitConfig;
+use crate::routing::{DeviceNotificationConfig, LevelRoutes, NotificationRouter, NotificationRule};
+use crate::spool::NotificationSpoolConfig;
+use crate::debounce::DebounceConfig;
 use crate::dummydevice::DummyDeviceMonitor;
-use crate::status::{StatusManager, StatusLevel};
+use crate::escalation::EscalationConfig;
+use crate::status::{StatusManager, StatusLevel, StatusServerConfig};
 
+mod api;
 mod backgroundtask;
+mod dashboard;
+mod debounce;
 mod dummydevice;
+mod escalation;
+mod homeassistant;
+mod mqtt;
 mod napcogemini;
 mod notification;
+mod quiethours;
+mod ratelimit;
+mod routing;
+mod spool;
 mod status;
+mod template;
 
 /// Cerberus monitor configration file format.
 #[derive(Serialize, Deserialize, Debug)]
 struct CerberusConfig {
     /// List of devices to monitor.
-    devices: Vec<DeviceType>,
+    devices: Vec<DeviceConfig>,
 
     /// Heartbeat time in seconds.
     /// 
@@ -29,11 +51,67 @@ struct CerberusConfig {
     /// heartbeat notifications.
     notification_heartbeat: u64,
 
-    /// Notification target for status updates.
-    status_notification_target: Option<NotificationTarget>,
+    /// Notification targets for status updates.
+    /// 
+    /// Either a single target or a list of targets with a delivery policy.
+    status_notification_target: Option<NotificationTargets>,
+
+    /// Notification targets for high-priority notifications.
+    /// 
+    /// Either a single target or a list of targets with a delivery policy.
+    alarm_notification_target: Option<NotificationTargets>,
+
+    /// Template for notification text, defaults to
+    /// `[{device_name}, {level}] {message}`.
+    /// 
+    /// Targets can override this with their own `template`.
+    notification_template: Option<String>,
+
+    /// Retry and persistence settings for undelivered notifications.
+    notification_spool: Option<NotificationSpoolConfig>,
+
+    /// Rate limit applied to each notification target.
+    notification_rate_limit: Option<RateLimitConfig>,
+
+    /// Global notification targets for each status level, levels that
+    /// aren't listed use their defaults.
+    #[serde(default)]
+    notification_levels: LevelRoutes,
+
+    /// Rules sending matching notifications to other targets, applied in
+    /// order after any per-device targets.
+    #[serde(default)]
+    notification_rules: Vec<NotificationRule>,
+
+    /// Escalation of unacknowledged alarms.
+    alarm_escalation: Option<EscalationConfig>,
+
+    /// Quiet hours for status notifications.
+    quiet_hours: Option<QuietHoursConfig>,
+
+    /// MQTT broker to publish device status updates to.
+    mqtt: Option<MqttConfig>,
 
-    /// Notification target for high-priority notifications.
-    alarm_notification_target: Option<NotificationTarget>,
+    /// Status HTTP server, enabled on port 8080 by default.
+    status_server: Option<StatusServerConfig>,
+}
+
+/// Cerberus monitor device entry.
+#[derive(Serialize, Deserialize, Debug)]
+struct DeviceConfig {
+    /// Device type and settings.
+    #[serde(flatten)]
+    device: DeviceType,
+
+    /// Device name used in notifications, defaults to `Device`.
+    name: Option<String>,
+
+    /// Notification targets for the device, replacing or extending the
+    /// global targets.
+    notifications: Option<DeviceNotificationConfig>,
+
+    /// Status debounce and flap detection for the device.
+    debounce: Option<DebounceConfig>,
 }
 
 /// Cerberus monitor device configuration.
@@ -62,6 +140,29 @@ enum DeviceType {
     }
 }
 
+impl DeviceConfig {
+    /// Stable key for the device, derived from its configuration so it
+    /// stays the same across restarts: the device name if set, otherwise
+    /// its serial port or its position in the device list.
+    fn key(&self, index: usize) -> String {
+        match (&self.name, &self.device) {
+            (Some(name), _) => name.clone(),
+            (None, DeviceType::NapcoGemini { port }) => port.clone(),
+            (None, DeviceType::Dummy { .. }) => format!("dummy_{}", index),
+        }
+    }
+}
+
+impl DeviceType {
+    /// Kind of device monitored for this device type.
+    fn kind(&self) -> DeviceKind {
+        match self {
+            DeviceType::Dummy { .. } => DeviceKind::Dummy,
+            DeviceType::NapcoGemini { .. } => DeviceKind::NapcoGemini,
+        }
+    }
+}
+
 lazy_static! {
     /// Atomic device ID counter.
     static ref NEXT_DEVICE_ID: AtomicU64 = {
@@ -70,9 +171,23 @@ lazy_static! {
 }
 
 /// Unique ID for device monitors.
-#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, Debug)]
 pub struct DeviceId (u64);
 
+impl Display for DeviceId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for DeviceId {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.parse()?))
+    }
+}
+
 impl Default for DeviceId {
     /// Create a unique device monitor ID.
     fn default() -> Self {
@@ -83,6 +198,35 @@ impl Default for DeviceId {
     }
 }
 
+/// Kind of device monitored by a device monitor.
+#[derive(Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum DeviceKind {
+    /// Dummy device for testing.
+    Dummy,
+
+    /// Napco Gemini alarm system.
+    NapcoGemini,
+}
+
+impl DeviceKind {
+    /// Manufacturer of the device.
+    pub fn manufacturer(&self) -> &'static str {
+        match self {
+            DeviceKind::Dummy => "Cerberus",
+            DeviceKind::NapcoGemini => "Napco",
+        }
+    }
+}
+
+impl Display for DeviceKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceKind::Dummy => write!(f, "Dummy"),
+            DeviceKind::NapcoGemini => write!(f, "Napco Gemini"),
+        }
+    }
+}
+
 /// Common trait for device managers.
 #[async_trait]
 pub trait DeviceMonitor {
@@ -94,13 +238,13 @@ pub trait DeviceMonitor {
 }
 
 /// Create a device monitor from a device configuration.
-fn create_device_monitor(device_config: &DeviceType, status_manger: &StatusManager) -> anyhow::Result<Box<dyn DeviceMonitor>> {
+fn create_device_monitor(id: DeviceId, device_config: &DeviceType, status_manger: &StatusManager) -> anyhow::Result<Box<dyn DeviceMonitor>> {
     match device_config {
         DeviceType::Dummy { states, period } => {
-            Ok(Box::new(DummyDeviceMonitor::new(status_manger.clone(), states.clone(), *period)?))
+            Ok(Box::new(DummyDeviceMonitor::new(id, status_manger.clone(), states.clone(), *period)?))
         },
         DeviceType::NapcoGemini { port } => {
-            Ok(Box::new(NapcoGeminiDeviceMonitor::new(status_manger.clone(), port.clone())?))
+            Ok(Box::new(NapcoGeminiDeviceMonitor::new(id, status_manger.clone(), port.clone())?))
         },
     }
 }
@@ -133,7 +277,80 @@ fn load_configuration() -> anyhow::Result<CerberusConfig> {
 /// extract the targets using a regex. This allows limited, remote
 /// error reporting even if the configuration file is badly malformed.
 fn load_configruation_notification_targets() -> anyhow::Result<Vec<NotificationTarget>> {
-    anyhow::bail!("not implemented");
+    let config_data = std::fs::read(config_path()?)?;
+    parse_notification_targets(&String::from_utf8_lossy(&config_data))
+}
+
+/// Extract notification targets from the text of a configuration file
+/// that cannot be deserialized, see `load_configruation_notification_targets`.
+fn parse_notification_targets(config_text: &str) -> anyhow::Result<Vec<NotificationTarget>> {
+    let mut targets = parse_notification_targets_unstructured(config_text);
+    if targets.is_empty() {
+        targets = parse_notification_targets_pattern(config_text)?;
+    }
+
+    if targets.is_empty() {
+        anyhow::bail!("no notification targets found in configuration file");
+    }
+
+    Ok(targets)
+}
+
+/// Configuration keys that may hold a notification target.
+const NOTIFICATION_TARGET_KEYS: [&str; 2] = ["status_notification_target", "alarm_notification_target"];
+
+/// Extract notification targets from a configuration file that is
+/// valid JSON but does not match the configuration format.
+/// 
+/// Targets that cannot be deserialized are skipped, duplicates are
+/// only returned once.
+fn parse_notification_targets_unstructured(config_text: &str) -> Vec<NotificationTarget> {
+    let mut targets = vec![];
+
+    let config_value: serde_json::Value = match serde_json::from_str(config_text) {
+        Ok(config_value) => config_value,
+        Err(_) => return targets,
+    };
+
+    if let Some(config_object) = config_value.as_object() {
+        for key in NOTIFICATION_TARGET_KEYS {
+            if let Some(target_value) = config_object.get(key) {
+                if let Ok(Some(found_targets)) = serde_json::from_value::<Option<NotificationTargets>>(target_value.clone()) {
+                    for target in found_targets.targets() {
+                        if !targets.contains(&target.target) {
+                            targets.push(target.target.clone());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    targets
+}
+
+/// Extract Discord webhook notification targets from a configuration
+/// file that cannot be parsed as JSON by searching for webhook URLs.
+/// 
+/// A URL must be followed by a closing quote or delimiter, so a token
+/// cut off at the end of a truncated file is not used.
+fn parse_notification_targets_pattern(config_text: &str) -> anyhow::Result<Vec<NotificationTarget>> {
+    let webhook_regex = Regex::new(r#"(https://(?:(?:canary|ptb)\.)?discord(?:app)?\.com/api/webhooks/[0-9]+/[A-Za-z0-9_\-]+)["'\s,}\]?]"#)?;
+
+    let mut targets = vec![];
+    for webhook_match in webhook_regex.captures_iter(config_text) {
+        let target = NotificationTarget::DiscordWebhook {
+            url: webhook_match[1].to_string(),
+            username: None,
+            embeds: false,
+            alarm_mention: None,
+        };
+        if !targets.contains(&target) {
+            targets.push(target);
+        }
+    }
+
+    Ok(targets)
 }
 
 #[tokio::main]
@@ -160,8 +377,9 @@ async fn main() {
             match notification_fallbacks {
                 Ok(notification_fallbacks) => {
                     log::info!("Parsed {} notification target fallbacks", notification_fallbacks.len());
+                    let notification = Notification::system(format!("Startup failed! Unable to parse configuration file: {}", err), StatusLevel::Alarm);
                     for target in notification_fallbacks {
-                        match notification::send_notification(&target, &format!("Startup failed! Unable to parse configuration file: {}", err)).await {
+                        match notification::send_notification(&target, &notification).await {
                             Ok(_) => log::info!("Startup failure notification sent sucessfully"),
                             Err(err) => log::error!("Unable to send startup failure notification: {}", err),
                         }
@@ -174,36 +392,78 @@ async fn main() {
         },
     };
 
-    let notification_manager = NotificationManager::new(config.status_notification_target.clone(), config.alarm_notification_target.clone());
+    let notification_heartbeat = match config.notification_heartbeat {
+        0 => None,
+        seconds => Some(Duration::from_secs(seconds)),
+    };
+    let notification_manager = NotificationManager::new(
+        notification_heartbeat,
+        config.notification_spool.clone(),
+        config.notification_rate_limit.clone(),
+        config.quiet_hours.clone(),
+        NotificationRouter::new(
+            config.status_notification_target.clone(),
+            config.alarm_notification_target.clone(),
+            config.notification_levels.clone(),
+            config.notification_rules.clone()),
+        config.notification_template.clone());
     let status_manager = StatusManager::new(notification_manager.clone());
 
     status_manager.log("Cerberus monitor started.", StatusLevel::Status).await;
 
     // Send warnings to any available notification targets if status or alarm notification targets are not configured.
-    if let None = config.status_notification_target {
+    if config.status_notification_target.as_ref().is_none_or(NotificationTargets::is_empty) {
         status_manager.log("No status notification target configured, status updates will not be sent.", StatusLevel::Warning).await;
     }
-    if let None = config.alarm_notification_target {
+    if config.alarm_notification_target.as_ref().is_none_or(NotificationTargets::is_empty) {
         status_manager.log("No alarm notification target configured, alarm updates will not be sent!", StatusLevel::Warning).await;
     }
 
+    // Start alarm escalation.
+    if let Some(escalation_config) = &config.alarm_escalation {
+        status_manager.start_alarm_escalation(escalation_config.clone()).await;
+    }
+
+    // Start MQTT publisher.
+    if let Some(mqtt_config) = &config.mqtt {
+        match MqttPublisher::new(mqtt_config) {
+            Ok(mqtt_publisher) => status_manager.set_mqtt_publisher(mqtt_publisher).await,
+            Err(err) => status_manager.log(format!("Could not start MQTT publisher: {}", err), StatusLevel::Warning).await,
+        }
+    }
+
     // Start status server.
-    if let Err(err) = status_manager.serve().await {
-        status_manager.log(format!("Could not start status web server: {}", err), StatusLevel::Warning).await;
+    let status_server_config = config.status_server.clone().unwrap_or_default();
+    if status_server_config.enabled {
+        if let Err(err) = status_manager.serve(&status_server_config).await {
+            status_manager.log(format!("Could not start status web server: {}", err), StatusLevel::Warning).await;
+        }
+    } else if config.alarm_escalation.is_some() {
+        status_manager.log("Status server is disabled, alarm acknowledgement links will not work.", StatusLevel::Warning).await;
     }
 
     // Create device monitors.
     let mut devices: Vec<Box<dyn DeviceMonitor>> = vec![];
-    for device_config in &config.devices {
-        let device_monitor = create_device_monitor(device_config, &status_manager);
+    for (index, device_config) in config.devices.iter().enumerate() {
+        // Set up the device before its monitor starts sending status updates.
+        let device_id = DeviceId::default();
+        if let Some(device_notifications) = &device_config.notifications {
+            notification_manager.set_device_targets(device_id, device_notifications.clone());
+        }
+        if let Some(debounce) = &device_config.debounce {
+            status_manager.set_device_debounce(device_id, debounce.clone()).await;
+        }
+        let device_name = device_config.name.clone().unwrap_or_else(|| "Device".to_string());
+        status_manager.register_device(device_id, device_config.device.kind(), device_name, device_config.key(index)).await;
+
+        let device_monitor = create_device_monitor(device_id, &device_config.device, &status_manager);
         match device_monitor {
             Ok(device_monitor) => {
                 //todo log::info!("Created device monitor.");
-                status_manager.register_device(&*device_monitor).await;
                 devices.push(device_monitor)
             },
             Err(err) => {
-                status_manager.log(format!("Could not create device monitor: {}", err), StatusLevel::Alarm).await;
+                status_manager.update_status(device_id, format!("Could not create device monitor: {}", err), StatusLevel::Alarm).await;
             },
         }
     }
@@ -216,5 +476,93 @@ async fn main() {
         device.shutdown().await;
     }
 
+    // Send the final notice and wait for queued notifications to be sent.
+    status_manager.log("Cerberus monitor stopping.", StatusLevel::Status).await;
+    status_manager.shutdown().await;
+    notification_manager.shutdown().await;
+
     std::process::exit(0);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATUS_WEBHOOK: &str = "https://discord.com/api/webhooks/123/status-token";
+    const ALARM_WEBHOOK: &str = "https://discord.com/api/webhooks/456/alarm_token";
+
+    /// Get the webhook URLs of Discord targets.
+    fn discord_urls(targets: &[NotificationTarget]) -> Vec<&str> {
+        targets.iter()
+            .filter_map(|target| match target {
+                NotificationTarget::DiscordWebhook { url, .. } => Some(url.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unknown_device_variant() {
+        let config_text = format!(r#"{{
+            "devices": [{{"Toaster": {{"port": "/dev/ttyUSB0"}}}}],
+            "notification_heartbeat": null,
+            "status_notification_target": {{"DiscordWebhook": {{"url": "{}"}}}},
+            "alarm_notification_target": {{"Webhook": {{"url": "https://example.com/alarm", "body": {{"Form": {{"text": "{{text}}"}}}}}}}}
+        }}"#, STATUS_WEBHOOK);
+        assert!(serde_json::from_str::<CerberusConfig>(&config_text).is_err());
+
+        let targets = parse_notification_targets_unstructured(&config_text);
+        assert_eq!(targets.len(), 2);
+        assert_eq!(discord_urls(&targets), [STATUS_WEBHOOK]);
+        assert!(matches!(&targets[1], NotificationTarget::Webhook { url, .. } if url == "https://example.com/alarm"));
+    }
+
+    #[test]
+    fn duplicate_targets() {
+        let config_text = format!(r#"{{
+            "status_notification_target": {{"DiscordWebhook": {{"url": "{0}"}}}},
+            "alarm_notification_target": {{"targets": [{{"DiscordWebhook": {{"url": "{0}"}}}}, {{"DiscordWebhook": {{"url": "{1}"}}}}], "policy": "Failover"}}
+        }}"#, STATUS_WEBHOOK, ALARM_WEBHOOK);
+        assert_eq!(discord_urls(&parse_notification_targets_unstructured(&config_text)), [STATUS_WEBHOOK, ALARM_WEBHOOK]);
+    }
+
+    #[test]
+    fn trailing_commas() {
+        let config_text = format!(r#"{{
+            "devices": [],
+            "status_notification_target": {{"DiscordWebhook": {{"url": "{}",}}}},
+            "alarm_notification_target": {{"DiscordWebhook": {{"url": "{}"}}}},
+        }}"#, STATUS_WEBHOOK, ALARM_WEBHOOK);
+        assert!(parse_notification_targets_unstructured(&config_text).is_empty());
+        assert_eq!(discord_urls(&parse_notification_targets(&config_text).unwrap()), [STATUS_WEBHOOK, ALARM_WEBHOOK]);
+    }
+
+    #[test]
+    fn truncated_file() {
+        let config_text = format!(r#"{{
+            "status_notification_target": {{"DiscordWebhook": {{"url": "{}"}}}},
+            "alarm_notification_target": {{"DiscordWebhook": {{"url": "{}"}}}},
+            "devices": [{{"Dummy": {{"states": [["ok", false]], "period": 10}}}}]
+        }}"#, STATUS_WEBHOOK, ALARM_WEBHOOK);
+        let truncated = &config_text[..config_text.find("devices").unwrap()];
+        assert!(parse_notification_targets_unstructured(truncated).is_empty());
+        assert_eq!(discord_urls(&parse_notification_targets(truncated).unwrap()), [STATUS_WEBHOOK, ALARM_WEBHOOK]);
+
+        // Cut inside the second webhook token, only the first is complete.
+        let truncated = &config_text[..config_text.find("alarm_token").unwrap() + "alarm".len()];
+        assert_eq!(discord_urls(&parse_notification_targets(truncated).unwrap()), [STATUS_WEBHOOK]);
+
+        // Cut right after the second webhook token, before its closing quote.
+        let truncated = &config_text[..config_text.find("alarm_token").unwrap() + "alarm_token".len()];
+        assert_eq!(discord_urls(&parse_notification_targets(truncated).unwrap()), [STATUS_WEBHOOK]);
+
+        let truncated = &config_text[..config_text.find("https").unwrap()];
+        assert!(parse_notification_targets(truncated).is_err());
+    }
+
+    #[test]
+    fn non_discord_urls_ignored() {
+        let config_text = r#"{"status_notification_target": {"Webhook": {"url": "https://example.com/hook"}},"#;
+        assert!(parse_notification_targets(config_text).is_err());
+    }
+}
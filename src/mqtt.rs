@@ -0,0 +1,386 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Outgoing, Packet, QoS, TlsConfiguration, Transport};
+use serde::{Serialize, Deserialize};
+use tokio::sync::Mutex;
+
+use crate::{DeviceId, DeviceKind, backgroundtask::BackgroundTask, homeassistant::{AnnouncedDevice, HomeAssistantDiscovery, PanelState}, status::StatusLevel};
+
+/// MQTT broker configuration.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct MqttConfig {
+    /// Broker host name.
+    pub host: String,
+
+    /// Broker port, defaults to 1883, or 8883 if TLS is enabled.
+    pub port: Option<u16>,
+
+    /// Client ID, defaults to `cerberus`.
+    pub client_id: Option<String>,
+
+    /// Optional broker username.
+    pub username: Option<String>,
+
+    /// Optional broker password.
+    pub password: Option<String>,
+
+    /// Connect to the broker with TLS, defaults to false.
+    #[serde(default)]
+    pub tls: bool,
+
+    /// Optional CA certificate file (PEM) to verify the broker with
+    /// instead of the system certificates.
+    pub ca_file: Option<String>,
+
+    /// QoS level (0, 1 or 2) for published messages, defaults to 0.
+    #[serde(default)]
+    pub qos: u8,
+
+    /// Prefix for all published topics, defaults to `cerberus`.
+    pub topic_prefix: Option<String>,
+
+    /// Home Assistant MQTT discovery prefix, usually `homeassistant`.
+    /// Discovery is disabled if not set.
+    pub discovery_prefix: Option<String>,
+}
+
+/// Device status message published to MQTT.
+#[derive(Serialize)]
+struct MqttStatus<'a> {
+    device_id: DeviceId,
+    device_name: &'a str,
+    level: StatusLevel,
+    message: &'a str,
+    timestamp: DateTime<Utc>,
+}
+
+/// MQTT publisher, publishes device status updates to an MQTT broker.
+/// 
+/// Topics, relative to the topic prefix:
+/// - `status`: `online` or `offline`, retained, set to `offline` by the
+///   broker if Cerberus disconnects unexpectedly.
+/// - `devices/{device_id}/state`: latest device status, retained.
+/// - `devices/{device_id}/events`: every device status update.
+#[derive(Clone)]
+pub struct MqttPublisher {
+    /// MQTT client handle.
+    client: AsyncClient,
+
+    /// Topic prefix.
+    topic_prefix: String,
+
+    /// QoS for published messages.
+    qos: QoS,
+
+    /// Home Assistant discovery, if enabled.
+    discovery: Option<Arc<HomeAssistantDiscovery>>,
+
+    /// Background task driving the MQTT connection.
+    task: Arc<Mutex<BackgroundTask<()>>>,
+}
+
+impl MqttPublisher {
+    /// Capacity of the outgoing request queue.
+    const REQUEST_CAPACITY: usize = 100;
+
+    /// Delay before reconnecting after a connection error.
+    const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+    /// Maximum time to wait for a clean disconnect on shutdown.
+    const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Create a new MQTT publisher and start connecting to the broker.
+    pub fn new(config: &MqttConfig) -> anyhow::Result<Self> {
+        let qos = rumqttc::qos(config.qos).map_err(|_| anyhow::anyhow!("invalid MQTT QoS {}", config.qos))?;
+        let topic_prefix = config.topic_prefix.clone().unwrap_or_else(|| "cerberus".to_string());
+        let status_topic = format!("{}/status", topic_prefix);
+
+        let port = config.port.unwrap_or(if config.tls { 8883 } else { 1883 });
+        let client_id = config.client_id.clone().unwrap_or_else(|| "cerberus".to_string());
+        let mut options = MqttOptions::new(client_id, &config.host, port);
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_last_will(LastWill::new(&status_topic, "offline", qos, true));
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            options.set_credentials(username, password);
+        }
+        if config.tls {
+            let tls_config = match &config.ca_file {
+                Some(ca_file) => TlsConfiguration::SimpleNative { ca: std::fs::read(ca_file)?, client_auth: None },
+                None => TlsConfiguration::Native,
+            };
+            options.set_transport(Transport::tls_with_config(tls_config));
+        }
+
+        let discovery = config.discovery_prefix.as_ref()
+            .map(|discovery_prefix| Arc::new(HomeAssistantDiscovery::new(discovery_prefix.clone(), topic_prefix.clone())));
+
+        let (client, eventloop) = AsyncClient::new(options, Self::REQUEST_CAPACITY);
+
+        let task_client = client.clone();
+        let task_discovery = discovery.clone();
+        let task = BackgroundTask::spawn(|shutdown_token| async move {
+            Self::run_eventloop(task_client, eventloop, status_topic, qos, task_discovery, shutdown_token).await
+        });
+
+        Ok(Self {
+            client,
+            topic_prefix,
+            qos,
+            discovery,
+            task: Arc::new(Mutex::new(task)),
+        })
+    }
+
+    /// Drive the MQTT connection until shutdown.
+    async fn run_eventloop(
+        client: AsyncClient,
+        mut eventloop: EventLoop,
+        status_topic: String,
+        qos: QoS,
+        discovery: Option<Arc<HomeAssistantDiscovery>>,
+        shutdown_token: tokio_util::sync::CancellationToken)
+    {
+        loop {
+            tokio::select! {
+                event = eventloop.poll() => {
+                    match event {
+                        Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                            log::info!("Connected to MQTT broker");
+                            if let Err(err) = client.try_publish(&status_topic, qos, true, "online") {
+                                log::error!("Failed to publish MQTT online status: {}", err);
+                            }
+
+                            // Announce devices and re-announce them whenever Home Assistant restarts.
+                            if let Some(discovery) = &discovery {
+                                if let Err(err) = client.try_subscribe(discovery.status_topic(), QoS::AtLeastOnce) {
+                                    log::error!("Failed to subscribe to Home Assistant status: {}", err);
+                                }
+                                discovery.announce_all(&client);
+                            }
+                        },
+                        Ok(Event::Incoming(Packet::Publish(publish))) => {
+                            if let Some(discovery) = &discovery {
+                                if publish.topic == discovery.status_topic() && &publish.payload[..] == b"online" {
+                                    discovery.announce_all(&client);
+                                }
+                            }
+                        },
+                        Ok(_) => {},
+                        Err(err) => {
+                            log::error!("MQTT connection error: {}", err);
+                            tokio::select! {
+                                _ = tokio::time::sleep(Self::RECONNECT_DELAY) => {},
+                                _ = shutdown_token.cancelled() => break,
+                            }
+                        },
+                    }
+                },
+
+                _ = shutdown_token.cancelled() => {
+                    break;
+                }
+            }
+        }
+
+        // Publish the offline status and disconnect cleanly so the last
+        // will isn't sent.
+        let _ = client.try_publish(&status_topic, qos, true, "offline");
+        let _ = client.try_disconnect();
+        let disconnect = async {
+            loop {
+                match eventloop.poll().await {
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) | Err(_) => break,
+                    Ok(_) => {},
+                }
+            }
+        };
+        if tokio::time::timeout(Self::SHUTDOWN_TIMEOUT, disconnect).await.is_err() {
+            log::warn!("Timed out disconnecting from MQTT broker");
+        }
+    }
+
+    /// Publish a device status update.
+    pub fn publish_status(&self, device_id: DeviceId, device_name: &str, message: &str, level: StatusLevel, timestamp: DateTime<Utc>) {
+        let status = MqttStatus {
+            device_id,
+            device_name,
+            level,
+            message,
+            timestamp,
+        };
+        let payload = match serde_json::to_vec(&status) {
+            Ok(payload) => payload,
+            Err(err) => {
+                log::error!("Failed to serialize MQTT status: {}", err);
+                return;
+            },
+        };
+
+        let state_topic = format!("{}/devices/{}/state", self.topic_prefix, device_id);
+        let events_topic = format!("{}/devices/{}/events", self.topic_prefix, device_id);
+        if let Err(err) = self.client.try_publish(state_topic, self.qos, true, payload.clone()) {
+            log::error!("Failed to publish MQTT device state: {}", err);
+        }
+        if let Err(err) = self.client.try_publish(events_topic, self.qos, false, payload) {
+            log::error!("Failed to publish MQTT device event: {}", err);
+        }
+    }
+
+    /// Announce a device to Home Assistant, if discovery is enabled.
+    /// 
+    /// `key` is a stable identifier for the device derived from its
+    /// configuration, so Home Assistant keeps its entities across restarts.
+    pub fn announce_device(&self, device_id: DeviceId, kind: DeviceKind, name: &str, key: &str) {
+        if let Some(discovery) = &self.discovery {
+            discovery.add_device(&self.client, AnnouncedDevice {
+                id: device_id,
+                kind,
+                name: name.to_string(),
+                key: key.to_string(),
+            });
+        }
+    }
+
+    /// Publish an alarm panel state, if discovery is enabled.
+    pub fn publish_panel_state(&self, device_id: DeviceId, panel_state: PanelState) {
+        if let Some(discovery) = &self.discovery {
+            let payload = match serde_json::to_value(panel_state) {
+                Ok(serde_json::Value::String(payload)) => payload,
+                _ => return,
+            };
+            if let Err(err) = self.client.try_publish(discovery.panel_topic(device_id), self.qos, true, payload) {
+                log::error!("Failed to publish MQTT panel state: {}", err);
+            }
+        }
+    }
+
+    /// Publish the offline status, disconnect from the broker and wait
+    /// for the connection to close.
+    pub async fn shutdown(&self) {
+        let mut task = self.task.lock().await;
+        if let Err(err) = task.finish().await {
+            log::warn!("MQTT publisher shutdown: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, sync::mpsc};
+
+    use super::*;
+
+    /// Message published to the mock broker: topic, payload and retain flag.
+    type Published = (String, String, bool);
+
+    /// Read an MQTT packet, returning its first header byte and its body,
+    /// or None when the connection is closed.
+    async fn read_packet(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+        let header = stream.read_u8().await.ok()?;
+        let mut length = 0;
+        let mut shift = 0;
+        loop {
+            let byte = stream.read_u8().await.ok()?;
+            length |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).await.ok()?;
+        Some((header, body))
+    }
+
+    /// Start a minimal MQTT broker that accepts one client, acknowledges
+    /// its packets and forwards published messages until it disconnects.
+    async fn mock_broker() -> (u16, mpsc::UnboundedReceiver<Published>) {
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            while let Some((header, body)) = read_packet(&mut stream).await {
+                let reply = match header >> 4 {
+                    // CONNECT, accepted.
+                    1 => vec![0x20, 0x02, 0x00, 0x00],
+                    // PUBLISH, acknowledged if sent with QoS 1.
+                    3 => {
+                        let topic_end = 2 + u16::from_be_bytes([body[0], body[1]]) as usize;
+                        let topic = String::from_utf8_lossy(&body[2..topic_end]).into_owned();
+                        let (payload_start, reply) = match (header >> 1) & 0x03 {
+                            0 => (topic_end, vec![]),
+                            _ => (topic_end + 2, vec![0x40, 0x02, body[topic_end], body[topic_end + 1]]),
+                        };
+                        let payload = String::from_utf8_lossy(&body[payload_start..]).into_owned();
+                        sender.send((topic, payload, header & 0x01 != 0)).unwrap();
+                        reply
+                    },
+                    // SUBSCRIBE, granted with QoS 0.
+                    8 => vec![0x90, 0x03, body[0], body[1], 0x00],
+                    // PINGREQ.
+                    12 => vec![0xd0, 0x00],
+                    // DISCONNECT.
+                    14 => break,
+                    _ => vec![],
+                };
+                if stream.write_all(&reply).await.is_err() {
+                    break;
+                }
+            }
+        });
+        (port, receiver)
+    }
+
+    #[test]
+    fn config_defaults() {
+        let config: MqttConfig = serde_json::from_value(serde_json::json!({ "host": "localhost" })).unwrap();
+        assert!(!config.tls);
+        assert_eq!(config.qos, 0);
+    }
+
+    #[tokio::test]
+    async fn publishes_status_and_discovery_to_broker() {
+        let (port, mut published) = mock_broker().await;
+        let config: MqttConfig = serde_json::from_value(serde_json::json!({
+            "host": "127.0.0.1",
+            "port": port,
+            "qos": 1,
+            "discovery_prefix": "homeassistant",
+        })).unwrap();
+        let publisher = MqttPublisher::new(&config).unwrap();
+        let device_id = DeviceId::default();
+        publisher.announce_device(device_id, DeviceKind::Dummy, "Front Door", "Front Door");
+        publisher.publish_status(device_id, "Front Door", "Door open", StatusLevel::Alarm, Utc::now());
+
+        // Wait for the connection before shutting down, the online status
+        // is published once the broker accepts it.
+        let online = ("cerberus/status".to_string(), "online".to_string(), true);
+        let mut messages = vec![];
+        while !messages.contains(&online) {
+            messages.push(published.recv().await.unwrap());
+        }
+        publisher.shutdown().await;
+        while let Some(message) = published.recv().await {
+            messages.push(message);
+        }
+        let topic = |topic: &str| messages.iter().find(|(published_topic, _, _)| published_topic == topic);
+
+        assert_eq!(messages.last(), Some(&("cerberus/status".to_string(), "offline".to_string(), true)));
+
+        let (_, state, retain) = topic(&format!("cerberus/devices/{}/state", device_id)).unwrap();
+        let state: serde_json::Value = serde_json::from_str(state).unwrap();
+        assert_eq!(state["message"], "Door open");
+        assert_eq!(state["level"], "Alarm");
+        assert!(retain);
+        let (_, _, retain) = topic(&format!("cerberus/devices/{}/events", device_id)).unwrap();
+        assert!(!retain);
+
+        let (_, discovery, retain) = topic("homeassistant/sensor/cerberus_front_door/status/config").unwrap();
+        let discovery: serde_json::Value = serde_json::from_str(discovery).unwrap();
+        assert_eq!(discovery["device"]["name"], "Front Door");
+        assert_eq!(discovery["unique_id"], "cerberus_front_door_status");
+        assert!(retain);
+    }
+}
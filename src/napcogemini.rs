@@ -6,6 +6,7 @@ use serialport::SerialPort;
 use crate::DeviceId;
 use crate::DeviceMonitor;
 use crate::backgroundtask::BackgroundTask;
+use crate::homeassistant::PanelState;
 use crate::status::StatusLevel;
 use crate::status::StatusManager;
 
@@ -39,12 +40,12 @@ impl NapcoSerialInterface {
             .timeout(Duration::from_millis(Self::PORT_TIMEOUT_MS))
             .open()?;
 
-        return Ok(NapcoSerialInterface {
+        Ok(NapcoSerialInterface {
             port,
             buffer: vec![0; Self::BUFFER_CAP],
             buffer_len: 0,
             error_count: 0,
-        });
+        })
     }
 
     /// Advance the buffer, discarding n bytes.
@@ -110,7 +111,7 @@ impl NapcoSerialInterface {
             return Some(message);
         }
 
-        return None;
+        None
     }
 
     fn keypad_status(status1: u8, status2: u8) -> String {
@@ -141,12 +142,25 @@ impl NapcoSerialInterface {
             (_, _) => None,
         };
 
-        return match status {
+        match status {
             Some(status) => status.to_string(),
             None => format!("Unknown ({:02X?},{:02X?})", status1, status2),
         }
     }
 
+    /// Map a decoded keypad status to an alarm panel state.
+    fn panel_state(keypad_status: &str) -> Option<PanelState> {
+        let state = keypad_status.split(',').next().unwrap_or_default();
+        match state {
+            "Ready" | "Zone Fault" => Some(PanelState::Disarmed),
+            "Arming" => Some(PanelState::Arming),
+            "Armed" => Some(PanelState::ArmedAway),
+            "Disarm" => Some(PanelState::Pending),
+            "ALARM" => Some(PanelState::Triggered),
+            _ => None,
+        }
+    }
+
     /// Attempt to decode a message from the panel to the keypad.
     /// If it is a keypad message, returns
     /// Some(keypad status, keypad line, keypad text).
@@ -189,9 +203,7 @@ pub struct NapcoGeminiDeviceMonitor {
 }
 
 impl NapcoGeminiDeviceMonitor {
-    pub fn new(status_manger: StatusManager, serial_port: String) -> anyhow::Result<Self> {
-        let id = Default::default();
-
+    pub fn new(id: DeviceId, status_manger: StatusManager, serial_port: String) -> anyhow::Result<Self> {
         let monitor_task = BackgroundTask::try_spawn(|shutdown_token| {
             let mut serial_interface = NapcoSerialInterface::new(&serial_port)?;
 
@@ -200,11 +212,20 @@ impl NapcoGeminiDeviceMonitor {
 
                 let mut last_line_0 = None;
                 let mut last_keypad_message = String::new();
+                let mut last_panel_state = None;
 
                 while !shutdown_token.is_cancelled() {
                     // Read a message off the bus.
                     if let Some(message) = serial_interface.read_message_vec() {
                         if let Some((keypad_status, keypad_line, keypad_text)) = NapcoSerialInterface::decode_keypad_message(&message) {
+                            let panel_state = NapcoSerialInterface::panel_state(&keypad_status);
+                            if panel_state.is_some() && panel_state != last_panel_state {
+                                if let Some(panel_state) = panel_state {
+                                    status_manger.update_panel_state(id, panel_state).await;
+                                }
+                                last_panel_state = panel_state;
+                            }
+
                             if keypad_line == 0 {
                                 // Store the first line of the message.
                                 last_line_0 = Some(keypad_text);
@@ -214,7 +235,10 @@ impl NapcoGeminiDeviceMonitor {
                                     let keypad_entire_text = format!("{} {}", last_line.trim(), keypad_text.trim()).trim().to_string();
                                     let keypad_message = format!("{} \"{}\"", keypad_status, keypad_entire_text);
                                     if keypad_message != last_keypad_message {
-                                        let level = if keypad_message.to_lowercase().contains("alarm") {
+                                        let keypad_message_lower = keypad_message.to_lowercase();
+                                        let level = if keypad_message_lower.contains("fire") || keypad_message_lower.contains("panic") {
+                                            StatusLevel::Critical
+                                        } else if keypad_message_lower.contains("alarm") {
                                             StatusLevel::Alarm
                                         } else {
                                             StatusLevel::Status
@@ -1,11 +1,15 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::{Arc, atomic::{AtomicU64, AtomicUsize, Ordering}}, time::Duration};
 
-use serde::{Serialize, Deserialize};
-use tokio::sync::mpsc;
-use tokio_util::sync::{DropGuard, CancellationToken};
+use chrono::{DateTime, Local, Utc};
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor, message::{Mailbox, header::ContentType}, transport::smtp::authentication::Credentials};
+use serde::{Serialize, Deserialize, Deserializer, de::Error as _};
+use tokio::{sync::{mpsc, watch, Mutex}, time::Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::{DeviceId, backgroundtask::BackgroundTask, quiethours::{QuietHours, QuietHoursAction, QuietHoursConfig}, ratelimit::{RateLimitConfig, RateLimited, RateLimiter}, routing::{DeviceNotificationConfig, NotificationRouter}, spool::{NotificationSpool, NotificationSpoolConfig, NotificationPriority}, status::StatusLevel, template};
 
 /// Target for notifications.
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
 pub enum NotificationTarget {
     /// Send notifications to a Discord webhook.
     DiscordWebhook {
@@ -14,6 +18,341 @@ pub enum NotificationTarget {
 
         /// Optional username to override the webhook's default.
         username: Option<String>,
+
+        /// Send notifications as embeds colored by status level instead
+        /// of plain text.
+        #[serde(default)]
+        embeds: bool,
+
+        /// Optional roles and users mentioned in alarm notifications.
+        alarm_mention: Option<DiscordMention>,
+    },
+
+    /// Send notifications to a generic HTTP webhook.
+    Webhook {
+        /// Webhook URL.
+        url: String,
+
+        /// HTTP method, defaults to POST.
+        method: Option<String>,
+
+        /// Additional HTTP headers.
+        headers: Option<HashMap<String, String>>,
+
+        /// Request body.
+        body: WebhookBody,
+    },
+
+    /// Send notifications to a Slack incoming webhook.
+    SlackWebhook {
+        /// Slack incoming webhook URL.
+        url: String,
+
+        /// Optional mention added to alarm notifications, in Slack's
+        /// message syntax, e.g. `<!channel>`, `<!subteam^ID>` or `<@USER_ID>`.
+        alarm_mention: Option<String>,
+    },
+
+    /// Send notifications to a Telegram chat through the Bot API.
+    Telegram {
+        /// Bot API token.
+        bot_token: String,
+
+        /// Chat ID or `@channelusername` to send notifications to.
+        chat_id: String,
+
+        /// Optional Bot API base URL, defaults to `https://api.telegram.org`.
+        api_base: Option<String>,
+    },
+
+    /// Send notifications by email.
+    Email {
+        /// SMTP server host name.
+        host: String,
+
+        /// SMTP server port, defaults to the standard port for the
+        /// connection security mode.
+        port: Option<u16>,
+
+        /// SMTP connection security.
+        security: SmtpSecurity,
+
+        /// Optional SMTP username.
+        username: Option<String>,
+
+        /// Optional SMTP password.
+        password: Option<String>,
+
+        /// Sender address.
+        #[serde(deserialize_with = "deserialize_email_address")]
+        from: String,
+
+        /// Recipient addresses, at least one is required.
+        #[serde(deserialize_with = "deserialize_email_addresses")]
+        to: Vec<String>,
+    },
+
+    /// Send push notifications through ntfy.
+    Ntfy {
+        /// Topic to publish to.
+        topic: String,
+
+        /// Optional access token.
+        access_token: Option<String>,
+
+        /// Optional server base URL, defaults to `https://ntfy.sh`.
+        base_url: Option<String>,
+    },
+
+    /// Send push notifications through a Gotify server.
+    Gotify {
+        /// Gotify server base URL.
+        base_url: String,
+
+        /// Application token.
+        app_token: String,
+    },
+}
+
+/// SMTP connection security mode.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum SmtpSecurity {
+    /// Unencrypted connection, only for trusted local relays.
+    None,
+
+    /// Upgrade the connection with STARTTLS, default port 587.
+    StartTls,
+
+    /// Implicit TLS, default port 465.
+    Tls,
+}
+
+/// Discord roles and users to mention.
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct DiscordMention {
+    /// Role IDs to mention.
+    #[serde(default)]
+    pub roles: Vec<String>,
+
+    /// User IDs to mention.
+    #[serde(default)]
+    pub users: Vec<String>,
+}
+
+/// Deserialize an email address, rejecting addresses that can't be
+/// parsed when the configuration is loaded rather than when an email is
+/// sent.
+fn deserialize_email_address<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let address = String::deserialize(deserializer)?;
+    match address.parse::<Mailbox>() {
+        Ok(_) => Ok(address),
+        Err(err) => Err(D::Error::custom(format!("invalid email address '{}': {}", address, err))),
+    }
+}
+
+/// Deserialize a non-empty list of email addresses, see
+/// `deserialize_email_address`.
+fn deserialize_email_addresses<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let addresses = Vec::<String>::deserialize(deserializer)?;
+    if addresses.is_empty() {
+        return Err(D::Error::custom("email target must have at least one recipient"));
+    }
+    for address in &addresses {
+        if let Err(err) = address.parse::<Mailbox>() {
+            return Err(D::Error::custom(format!("invalid email address '{}': {}", address, err)));
+        }
+    }
+    Ok(addresses)
+}
+
+/// Request body for a generic HTTP webhook.
+/// 
+/// Body templates may reference the notification with the placeholders
+/// `{text}`, `{message}`, `{level}`, `{device_name}`, `{device_id}`
+/// and `{timestamp}`.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum WebhookBody {
+    /// JSON body template, placeholder values are escaped for use inside
+    /// JSON strings.
+    Json(String),
+
+    /// Form body, each field value is a template.
+    Form(HashMap<String, String>),
+}
+
+/// Notification sent to notification targets.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Notification {
+    /// Formatted notification text.
+    pub text: String,
+
+    /// Status message the notification was created from.
+    pub message: String,
+
+    /// Status level.
+    pub level: StatusLevel,
+
+    /// Name of the device the notification is about.
+    pub device_name: String,
+
+    /// ID of the device the notification is about, if any.
+    pub device_id: Option<DeviceId>,
+
+    /// Time of the status update.
+    pub timestamp: DateTime<Utc>,
+
+    /// Text of each notification merged into this one, for digests of
+    /// rate limited notifications.
+    #[serde(default)]
+    pub digest: Vec<String>,
+
+    /// Link to acknowledge the alarm, for alarms that escalate.
+    #[serde(default)]
+    pub ack_url: Option<String>,
+
+    /// Device's previous status message, if any.
+    #[serde(default)]
+    pub previous_message: Option<String>,
+
+    /// Device's previous status level, if any.
+    #[serde(default)]
+    pub previous_level: Option<StatusLevel>,
+}
+
+impl Notification {
+    /// Create a notification that is not about a specific device.
+    pub fn system<T: ToString>(text: T, level: StatusLevel) -> Self {
+        let text = text.to_string();
+        Self {
+            message: text.clone(),
+            text,
+            level,
+            device_name: "Cerberus".to_string(),
+            device_id: None,
+            timestamp: Utc::now(),
+            digest: vec![],
+            ack_url: None,
+            previous_message: None,
+            previous_level: None,
+        }
+    }
+}
+
+/// Delivery policy for a list of notification targets.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum DeliveryPolicy {
+    /// Deliver notifications to every target.
+    FanOut,
+
+    /// Deliver notifications to the first target, trying each following
+    /// target in order only if the previous one fails.
+    Failover,
+}
+
+/// Notification target with its per-target settings.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct NotificationTargetConfig {
+    /// Notification target.
+    #[serde(flatten)]
+    pub target: NotificationTarget,
+
+    /// Optional message template, overriding the global template.
+    #[serde(default)]
+    pub template: Option<String>,
+}
+
+/// One or more notification targets for a notification priority.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum NotificationTargets {
+    /// A single notification target.
+    Single(NotificationTargetConfig),
+
+    /// A list of notification targets.
+    List {
+        /// Notification targets.
+        targets: Vec<NotificationTargetConfig>,
+
+        /// How notifications are delivered to the targets.
+        policy: DeliveryPolicy,
+    },
+}
+
+impl NotificationTargetConfig {
+    /// Send a notification to the target, rendering the target's message
+    /// template, or `default_template` if it has none.
+    pub async fn send(&self, notification: &Notification, default_template: Option<&str>) -> anyhow::Result<()> {
+        let templated = self.apply_template(notification, default_template);
+        send_notification(&self.target, templated.as_ref().unwrap_or(notification)).await
+    }
+
+    /// Render the target's message template, or `default_template` if it
+    /// has none, for a notification and add the acknowledgement link for
+    /// alarms unless the template did. Returns the updated notification,
+    /// or None if there is nothing to change.
+    /// 
+    /// Targets that show the device and level separately from the message
+    /// body use the rendered template as the message.
+    fn apply_template(&self, notification: &Notification, default_template: Option<&str>) -> Option<Notification> {
+        let rendered = self.template.as_deref().or(default_template)
+            .map(|template| template::render(template, notification, template::escape_none));
+        if rendered.is_none() && notification.ack_url.is_none() {
+            return None;
+        }
+
+        let mut templated = notification.clone();
+        if let Some(rendered) = rendered {
+            if matches!(self.target, NotificationTarget::DiscordWebhook { embeds: true, .. }
+                | NotificationTarget::SlackWebhook { .. }
+                | NotificationTarget::Ntfy { .. }
+                | NotificationTarget::Gotify { .. })
+            {
+                templated.message = rendered.clone();
+            }
+            templated.text = rendered;
+        }
+        if let Some(ack_url) = &notification.ack_url {
+            if !templated.text.contains(ack_url.as_str()) {
+                templated.text = format!("{}\nAcknowledge: {}", templated.text, ack_url);
+            }
+        }
+        Some(templated)
+    }
+}
+
+impl NotificationTargets {
+    /// Get the list of notification targets.
+    pub fn targets(&self) -> &[NotificationTargetConfig] {
+        match self {
+            NotificationTargets::Single(target) => std::slice::from_ref(target),
+            NotificationTargets::List { targets, .. } => targets,
+        }
+    }
+
+    /// Get the delivery policy for the targets.
+    pub fn policy(&self) -> DeliveryPolicy {
+        match self {
+            NotificationTargets::Single(_) => DeliveryPolicy::FanOut,
+            NotificationTargets::List { policy, .. } => *policy,
+        }
+    }
+
+    /// Returns true if there are no notification targets.
+    pub fn is_empty(&self) -> bool {
+        self.targets().is_empty()
+    }
+
+    /// Split the targets into delivery lanes, with the index of each
+    /// lane's first target.
+    /// 
+    /// Fan-out targets are each delivered to in their own lane, so a
+    /// target that is down doesn't hold back the others. Failover targets
+    /// share a single lane.
+    pub fn lanes(&self) -> Vec<(usize, NotificationTargets)> {
+        match self.policy() {
+            DeliveryPolicy::FanOut => self.targets().iter().cloned().map(NotificationTargets::Single).enumerate().collect(),
+            DeliveryPolicy::Failover => vec![(0, self.clone())],
+        }
     }
 }
 
@@ -21,31 +360,85 @@ pub enum NotificationTarget {
 /// to the configured notification targets.
 #[derive(Clone)]
 pub struct NotificationManager {
-    /// Status notification channel sender.
-    status_sender: mpsc::UnboundedSender<String>,
+    /// Status notification channel sender, with the targets to send the
+    /// notification to.
+    status_sender: mpsc::UnboundedSender<(Notification, NotificationTargets)>,
 
-    /// Alarm notification channel sender.
-    alarm_sender: mpsc::UnboundedSender<String>,
+    /// Alarm notification channel sender, with the targets to send the
+    /// alarm to.
+    alarm_sender: mpsc::UnboundedSender<(Notification, NotificationTargets)>,
 
-    /// Drop guard to shut down the notification manager's background
-    /// task once the last handle to the manager is dropped.
-    /// 
-    /// Note, this is never read, by design.
-    #[allow(dead_code)]
-    cancelation_dropguard: Arc<DropGuard>,
+    /// Latest device status summary, sent as the heartbeat message.
+    heartbeat_sender: Arc<watch::Sender<String>>,
+
+    /// Number of notifications waiting to be delivered.
+    pending_count: Arc<AtomicUsize>,
+
+    /// Number of delivery attempts rejected by a target's rate limit.
+    rate_limited_count: Arc<AtomicU64>,
+
+    /// Notification router, shared with the background task.
+    router: Arc<NotificationRouter>,
+
+    /// Notification manager background task, shut down once the last
+    /// handle to the manager is dropped or `shutdown()` is called.
+    background_task: Arc<Mutex<BackgroundTask<()>>>,
 }
 
 impl NotificationManager {
     /// Create a new NotificationManager.
-    pub fn new(status_target: Option<NotificationTarget>, alarm_target: Option<NotificationTarget>) -> Self {
-        let shutdown_token = CancellationToken::new();
-        let cancelation_dropguard = shutdown_token.clone().drop_guard();
-
+    /// 
+    /// Notifications are sent to the targets selected by `router`. If
+    /// `heartbeat` is set, the latest heartbeat status is sent to the
+    /// status notification target whenever no notification has been
+    /// sent for the heartbeat duration. Undelivered notifications are
+    /// retried, and kept on disk if a spool directory is configured.
+    /// Status notifications sent during `quiet_hours` are held or dropped.
+    /// Notification text is rendered from `template` for targets without
+    /// their own template, see `template::render` for the supported
+    /// placeholders.
+    pub fn new(
+        heartbeat: Option<Duration>,
+        spool_config: Option<NotificationSpoolConfig>,
+        rate_limit: Option<RateLimitConfig>,
+        quiet_hours: Option<QuietHoursConfig>,
+        router: NotificationRouter,
+        template: Option<String>)
+     -> Self
+    {
         let (status_sender, status_receiver) = mpsc::unbounded_channel();
         let (alarm_sender, alarm_receiver) = mpsc::unbounded_channel();
+        let (heartbeat_sender, heartbeat_receiver) = watch::channel("Cerberus monitor is running.".to_string());
+        let pending_count: Arc<AtomicUsize> = Default::default();
+        let rate_limited_count: Arc<AtomicU64> = Default::default();
+
+        let spool = match &spool_config {
+            Some(spool_config) => NotificationSpool::open(spool_config).unwrap_or_else(|err| {
+                log::error!("Unable to open notification spool, undelivered notifications will not be saved: {}", err);
+                NotificationSpool::in_memory()
+            }),
+            None => NotificationSpool::in_memory(),
+        };
+        pending_count.store(spool.len(), Ordering::Relaxed);
+
+        let router = Arc::new(router);
 
-        tokio::spawn(async move {
-            if let Err(err) = Self::background_task(status_target, alarm_target, status_receiver, alarm_receiver, shutdown_token).await {
+        let worker = NotificationWorker {
+            router: router.clone(),
+            heartbeat,
+            spool,
+            pending_count: pending_count.clone(),
+            rate_limited_count: rate_limited_count.clone(),
+            last_notification: Instant::now(),
+            rate_limiter: RateLimiter::new(rate_limit),
+            lanes: vec![],
+            next_attempt: None,
+            quiet_hours: quiet_hours.map(QuietHours::new),
+            template,
+        };
+
+        let background_task = BackgroundTask::spawn(|shutdown_token| async move {
+            if let Err(err) = worker.run(status_receiver, alarm_receiver, heartbeat_receiver, shutdown_token).await {
                 log::error!("Notification manager background task failed: {}", err);
             } else {
                 log::info!("Notification manager background task finished");
@@ -55,80 +448,1369 @@ impl NotificationManager {
         Self {
             status_sender,
             alarm_sender,
-            cancelation_dropguard: Arc::new(cancelation_dropguard),
+            heartbeat_sender: Arc::new(heartbeat_sender),
+            pending_count,
+            rate_limited_count,
+            router,
+            background_task: Arc::new(Mutex::new(background_task)),
+        }
+    }
+
+    /// Number of notifications waiting to be delivered.
+    pub fn pending_notifications(&self) -> usize {
+        self.pending_count.load(Ordering::Relaxed)
+    }
+
+    /// Number of delivery attempts rejected by a notification target's
+    /// own rate limit, such as a Discord 429 response.
+    pub fn rate_limited_attempts(&self) -> u64 {
+        self.rate_limited_count.load(Ordering::Relaxed)
+    }
+
+    /// Stop the notification manager, sending any queued notifications
+    /// before returning.
+    /// 
+    /// Notifications sent after shutdown are dropped.
+    pub async fn shutdown(&self) {
+        let mut background_task = self.background_task.lock().await;
+        if let Err(err) = background_task.finish().await {
+            log::warn!("Notification manager shutdown: {}", err);
+        }
+    }
+
+    /// Send a device's notifications to its own targets.
+    pub fn set_device_targets(&self, device_id: DeviceId, config: DeviceNotificationConfig) {
+        self.router.set_device_targets(device_id, config);
+    }
+
+    /// Send a notification to the targets it is routed to for each
+    /// priority of its level.
+    /// 
+    /// Notifications of levels that aren't sent to any global targets,
+    /// such as `Info` by default, only go to the targets of rules that
+    /// list their level.
+    pub fn send(&self, notification: Notification) {
+        for (priority, targets) in self.router.route(&notification) {
+            match priority {
+                NotificationPriority::Status => self.send_status_to(targets, notification.clone()),
+                NotificationPriority::Alarm => self.send_alarm_to(targets, notification.clone()),
+            }
+        }
+    }
+
+    /// Send a status notification to specific targets, bypassing routing.
+    pub fn send_status_to(&self, targets: NotificationTargets, notification: Notification) {
+        if let Err(err) = self.status_sender.send((notification, targets)) {
+            log::error!("Failed to send status message '{}', notification manager is stopped", err.0.0.text);
         }
     }
 
-    /// Notification manager background task.
-    async fn background_task(
-        status_target: Option<NotificationTarget>,
-        alarm_target: Option<NotificationTarget>,
-        mut status_receiver: mpsc::UnboundedReceiver<String>,
-        mut alarm_receiver: mpsc::UnboundedReceiver<String>,
+    /// Set the status message sent with the next heartbeat notification.
+    pub fn set_heartbeat_status<T: ToString> (&self, message: T) {
+        self.heartbeat_sender.send_replace(message.to_string());
+    }
+
+    /// Send an alarm notification to specific targets, bypassing routing.
+    pub fn send_alarm_to(&self, targets: NotificationTargets, notification: Notification) {
+        if let Err(err) = self.alarm_sender.send((notification, targets)) {
+            log::error!("Failed to send alarm message '{}', notification manager is stopped", err.0.0.text);
+        }
+    }
+}
+
+/// Delivery lane backing off after failed or rate limited delivery
+/// attempts.
+/// 
+/// A notification waiting for a lane holds back any later notifications
+/// to the same lane, so each target receives notifications in order.
+struct DeliveryLane {
+    /// Notification priority.
+    priority: NotificationPriority,
+
+    /// Targets the lane delivers to.
+    targets: NotificationTargets,
+
+    /// Number of consecutive failed delivery attempts.
+    failures: u32,
+
+    /// Time of the next delivery attempt.
+    next_attempt: Instant,
+
+    /// Whether the lane is held back by the local rate limit, in which
+    /// case status notifications that queue up for it are merged into a
+    /// digest when released. Notifications held back by a target's own
+    /// rate limit are redelivered in order.
+    locally_throttled: bool,
+}
+
+/// Notification manager background task state.
+struct NotificationWorker {
+    /// Notification router, for the global targets of each priority.
+    router: Arc<NotificationRouter>,
+
+    /// Heartbeat interval, if enabled.
+    heartbeat: Option<Duration>,
+
+    /// Queued notifications.
+    spool: NotificationSpool,
+
+    /// Number of queued notifications, shared with the manager handles.
+    pending_count: Arc<AtomicUsize>,
+
+    /// Number of rate limited delivery attempts, shared with the manager
+    /// handles.
+    rate_limited_count: Arc<AtomicU64>,
+
+    /// Time the last notification was sent, used to schedule heartbeats.
+    last_notification: Instant,
+
+    /// Per-target rate limiter.
+    rate_limiter: RateLimiter,
+
+    /// Delivery lanes backing off after failed or rate limited delivery
+    /// attempts.
+    lanes: Vec<DeliveryLane>,
+
+    /// Time of the next delivery attempt, if any queued notifications are
+    /// waiting for a retry or for rate limiting to end.
+    next_attempt: Option<Instant>,
+
+    /// Quiet hours schedule, if configured.
+    quiet_hours: Option<QuietHours>,
+
+    /// Message template for targets without their own template.
+    template: Option<String>,
+}
+
+impl NotificationWorker {
+    /// Maximum time to spend sending queued notifications on shutdown.
+    const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(15);
+
+    /// Run the notification manager background task.
+    async fn run(
+        mut self,
+        mut status_receiver: mpsc::UnboundedReceiver<(Notification, NotificationTargets)>,
+        mut alarm_receiver: mpsc::UnboundedReceiver<(Notification, NotificationTargets)>,
+        heartbeat_receiver: watch::Receiver<String>,
         shutdown_token: CancellationToken)
      -> anyhow::Result<()>
     {
+        // Send anything left in the spool from a previous run, releasing
+        // held notifications if their quiet hours ended in the meantime.
+        self.release_held(false);
+        self.deliver().await;
+
         loop {
+            // Wait for the next heartbeat, or forever if heartbeats are disabled.
+            let heartbeat = self.heartbeat;
+            let last_notification = self.last_notification;
+            let next_heartbeat = async move {
+                match heartbeat {
+                    Some(heartbeat) => tokio::time::sleep_until(last_notification + heartbeat).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            // Wait for the next retry, or forever if nothing is queued.
+            let next_attempt = self.next_attempt;
+            let next_retry = async move {
+                match next_attempt {
+                    Some(next_attempt) => tokio::time::sleep_until(next_attempt).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            // Wait for the end of quiet hours while notifications are held.
+            let next_release = self.spool.next_release();
+            let quiet_hours_end = async move {
+                match next_release {
+                    Some(next_release) => tokio::time::sleep((next_release - Utc::now()).to_std().unwrap_or_default()).await,
+                    None => std::future::pending().await,
+                }
+            };
+
             tokio::select! {
-                Some(status) = status_receiver.recv() => {
-                    if let Some(status_target) = &status_target {
-                        if let Err(err) = send_notification(status_target, &status).await {
-                            log::error!("Failed to send status notification '{}': {}", status, err);
-                        }
-                    }
+                Some((status, targets)) = status_receiver.recv() => {
+                    self.queue(NotificationPriority::Status, status, targets);
                 },
 
-                Some(alarm) = alarm_receiver.recv() => {
-                    if let Some(alarm_target) = &alarm_target {
-                        if let Err(err) = send_notification(alarm_target, &alarm).await {
-                            log::error!("Failed to send alarm notification '{}': {}", alarm, err);
+                Some((alarm, targets)) = alarm_receiver.recv() => {
+                    self.queue(NotificationPriority::Alarm, alarm, targets);
+                },
+
+                _ = next_retry => {},
+
+                _ = quiet_hours_end => {},
+
+                _ = next_heartbeat => {
+                    let heartbeat_notification = Notification::system(heartbeat_receiver.borrow().clone(), StatusLevel::Status);
+                    let status_targets = self.router.targets(NotificationPriority::Status).cloned();
+
+                    // Heartbeats are skipped during quiet hours.
+                    if let (Some(status_targets), None) = (status_targets, self.quiet_hours_action(&heartbeat_notification)) {
+                        if let Err(err) = send_notification_targets(&status_targets, &heartbeat_notification, self.template.as_deref(), &mut vec![], &mut self.rate_limiter).await {
+                            log::error!("Failed to send heartbeat notification: {}", err);
                         }
+                        self.rate_limited_count.store(self.rate_limiter.rejected_count(), Ordering::Relaxed);
                     }
+                    self.last_notification = Instant::now();
                 },
 
                 _ = shutdown_token.cancelled() => {
                     break;
                 }
             }
+
+            self.release_held(false);
+            self.deliver().await;
         }
 
-        // Clean up and attempt to send remaining messages.
+        // Clean up and attempt to send remaining messages, alarms first.
         status_receiver.close();
         alarm_receiver.close();
-        // todo
+        while let Some((alarm, targets)) = alarm_receiver.recv().await {
+            self.queue(NotificationPriority::Alarm, alarm, targets);
+        }
+        while let Some((status, targets)) = status_receiver.recv().await {
+            self.queue(NotificationPriority::Status, status, targets);
+        }
+
+        // Held notifications wait in a durable spool for quiet hours to
+        // end, otherwise they are sent now rather than lost.
+        self.release_held(!self.spool.is_durable());
+
+        let flush = async {
+            loop {
+                self.deliver().await;
+                match self.next_attempt {
+                    Some(next_attempt) => tokio::time::sleep_until(next_attempt).await,
+                    None => break,
+                }
+            }
+        };
+
+        if tokio::time::timeout(Self::SHUTDOWN_FLUSH_TIMEOUT, flush).await.is_err() {
+            if self.spool.is_durable() {
+                log::warn!("Timed out sending queued notifications, {} notifications left in spool", self.spool.len());
+            } else {
+                log::error!("Timed out sending queued notifications, {} notifications dropped", self.spool.len());
+            }
+        }
 
         Ok(())
     }
 
-    /// Send a status message to the status notification target.
-    pub fn send_status<T: ToString> (&self, message: T) {
-        if let Err(err) = self.status_sender.send(message.to_string()) {
-            log::error!("Failed to send status message '{}', notification manager is stopped", err.0);
+    /// Queue a notification to `targets`, dropping it if there are no
+    /// targets.
+    /// 
+    /// Status notifications are held or dropped during quiet hours.
+    fn queue(&mut self, priority: NotificationPriority, notification: Notification, targets: NotificationTargets) {
+        if targets.is_empty() {
+            return;
+        }
+
+        // Only keep routed targets that differ from the priority's targets,
+        // so spooled notifications follow configuration changes.
+        let targets = if Some(&targets) == self.router.targets(priority) { None } else { Some(targets) };
+
+        match self.quiet_hours_action(&notification) {
+            Some(QuietHoursAction::Suppress) => {
+                log::info!("Dropping notification '{}' during quiet hours", notification.text);
+            },
+            Some(QuietHoursAction::Hold) => {
+                let now = Utc::now();
+                let release_time = self.quiet_hours.as_ref()
+                    .map_or(now, |quiet_hours| quiet_hours.release_time(notification.level, now));
+                self.spool.hold(notification, targets, release_time);
+            },
+            None => {
+                // Send anything held from quiet hours that just ended first.
+                self.release_held(false);
+                self.spool.push(priority, notification, targets);
+            },
+        }
+        self.update_pending_count();
+    }
+
+    /// Get the quiet hours action for a notification, or None if it
+    /// should be sent now.
+    fn quiet_hours_action(&self, notification: &Notification) -> Option<QuietHoursAction> {
+        self.quiet_hours.as_ref()?.action(notification.level, Utc::now())
+    }
+
+    /// Queue held notifications whose quiet hours have ended as a digest
+    /// for each set of targets, or all held notifications if `all` is set.
+    /// 
+    /// The release time of notifications that are still held is updated,
+    /// in case the quiet hours configuration changed since they were held.
+    fn release_held(&mut self, all: bool) {
+        let now = Utc::now();
+        let quiet_hours = self.quiet_hours.as_ref();
+        let released = self.spool.release(|entry| {
+            let level = entry.notification.level;
+            match quiet_hours {
+                Some(quiet_hours) if !all && quiet_hours.action(level, now).is_some() => {
+                    entry.held_until = Some(quiet_hours.release_time(level, now));
+                    false
+                },
+                _ => true,
+            }
+        });
+
+        let mut groups: Vec<(Option<NotificationTargets>, Vec<Notification>)> = vec![];
+        for entry in released {
+            match groups.iter_mut().find(|(targets, _)| *targets == entry.targets) {
+                Some((_, notifications)) => notifications.push(entry.notification),
+                None => groups.push((entry.targets, vec![entry.notification])),
+            }
+        }
+
+        for (targets, mut notifications) in groups {
+            let notification = match notifications.len() {
+                1 => notifications.remove(0),
+                _ => Self::digest(notifications, "held during quiet hours"),
+            };
+            self.spool.push(NotificationPriority::Status, notification, targets);
+        }
+        self.update_pending_count();
+    }
+
+    /// Update the number of notifications waiting to be delivered.
+    fn update_pending_count(&self) {
+        self.pending_count.store(self.spool.len(), Ordering::Relaxed);
+    }
+
+    /// Attempt to deliver all queued notifications that are due, alarms first.
+    /// 
+    /// Each notification is delivered to its targets lane by lane, a lane
+    /// that fails is retried with exponential backoff and a rate limited
+    /// lane is held until the rate limit allows it to be sent to, without
+    /// holding back notifications to other lanes. Status notifications
+    /// that queue up while a lane is held back by the local rate limit
+    /// are merged into a single digest notification.
+    async fn deliver(&mut self) {
+        self.spool.expire();
+        self.next_attempt = None;
+
+        for priority in [NotificationPriority::Alarm, NotificationPriority::Status] {
+            if self.spool.queue_len(priority) == 0 {
+                continue;
+            }
+
+            if priority == NotificationPriority::Status {
+                self.coalesce_throttled();
+            }
+
+            // Lanes that an earlier notification is waiting for.
+            let mut waiting: Vec<NotificationTargets> = vec![];
+            for id in self.spool.queued_ids(priority) {
+                let entry = match self.spool.get(priority, id) {
+                    Some(entry) => entry,
+                    None => continue,
+                };
+                let targets = entry.targets.as_ref().or_else(|| self.router.targets(priority));
+                let targets = match targets {
+                    Some(targets) => targets.clone(),
+                    None => {
+                        // Target was removed from configuration since the notification was spooled.
+                        log::warn!("Discarding spooled {:?} notification '{}', no target configured", priority, entry.notification.text);
+                        self.spool.delivered(priority, id);
+                        continue;
+                    },
+                };
+
+                let notification = entry.notification.clone();
+                let attempt = entry.attempts + 1;
+                let mut delivered_targets = entry.delivered_targets.clone();
+                let lanes = targets.lanes();
+                let mut errors = vec![];
+                for (index, lane) in &lanes {
+                    if delivered_targets.contains(index) || waiting.contains(lane) {
+                        continue;
+                    }
+                    if let Some(next_attempt) = self.lane_next_attempt(priority, lane) {
+                        self.schedule(next_attempt);
+                        waiting.push(lane.clone());
+                        continue;
+                    }
+
+                    match send_notification_targets(lane, &notification, self.template.as_deref(), &mut vec![], &mut self.rate_limiter).await {
+                        Ok(_) => {
+                            delivered_targets.push(*index);
+                            self.lanes.retain(|state| state.priority != priority || state.targets != *lane);
+                            self.last_notification = Instant::now();
+                        },
+                        Err(err) => match err.downcast::<RateLimited>() {
+                            Ok(rate_limited) => {
+                                match targets.policy() {
+                                    DeliveryPolicy::FanOut => log::warn!("{:?} notifications to target {} {}", priority, index, rate_limited),
+                                    DeliveryPolicy::Failover => log::warn!("{:?} notifications {}", priority, rate_limited),
+                                }
+                                let state = self.lane_mut(priority, lane);
+                                state.next_attempt = rate_limited.until;
+                                state.locally_throttled = !rate_limited.by_target;
+                                self.schedule(rate_limited.until);
+                                waiting.push(lane.clone());
+                            },
+                            Err(err) => {
+                                let state = self.lane_mut(priority, lane);
+                                state.failures += 1;
+                                state.next_attempt = Instant::now() + NotificationSpool::backoff(state.failures);
+                                state.locally_throttled = false;
+                                let next_attempt = state.next_attempt;
+                                self.schedule(next_attempt);
+                                waiting.push(lane.clone());
+                                match targets.policy() {
+                                    DeliveryPolicy::FanOut => errors.push(format!("target {}: {}", index, err)),
+                                    DeliveryPolicy::Failover => errors.push(err.to_string()),
+                                }
+                            },
+                        },
+                    }
+                }
+
+                if lanes.iter().all(|(index, _)| delivered_targets.contains(index)) {
+                    self.spool.delivered(priority, id);
+                } else if !errors.is_empty() {
+                    log::error!("Failed to send {:?} notification '{}' (attempt {}): {}", priority, notification.text, attempt, errors.join(", "));
+                    self.spool.failed(priority, id, delivered_targets);
+                } else {
+                    self.spool.progress(priority, id, delivered_targets);
+                }
+            }
+        }
+
+        self.update_pending_count();
+        self.rate_limited_count.store(self.rate_limiter.rejected_count(), Ordering::Relaxed);
+    }
+
+    /// Time a lane is backing off until, or None if it can be delivered
+    /// to now.
+    fn lane_next_attempt(&self, priority: NotificationPriority, targets: &NotificationTargets) -> Option<Instant> {
+        self.lanes.iter()
+            .find(|lane| lane.priority == priority && lane.targets == *targets)
+            .map(|lane| lane.next_attempt)
+            .filter(|next_attempt| *next_attempt > Instant::now())
+    }
+
+    /// Get the state of a lane to record a failed or rate limited
+    /// delivery attempt.
+    fn lane_mut(&mut self, priority: NotificationPriority, targets: &NotificationTargets) -> &mut DeliveryLane {
+        let index = match self.lanes.iter().position(|lane| lane.priority == priority && lane.targets == *targets) {
+            Some(index) => index,
+            None => {
+                self.lanes.push(DeliveryLane {
+                    priority,
+                    targets: targets.clone(),
+                    failures: 0,
+                    next_attempt: Instant::now(),
+                    locally_throttled: false,
+                });
+                self.lanes.len() - 1
+            },
+        };
+        &mut self.lanes[index]
+    }
+
+    /// Merge the status notifications that queued up for lanes released
+    /// from the local rate limit into a digest for each set of targets.
+    fn coalesce_throttled(&mut self) {
+        let now = Instant::now();
+        let released: Vec<NotificationTargets> = self.lanes.iter_mut()
+            .filter(|lane| lane.priority == NotificationPriority::Status && lane.locally_throttled && lane.next_attempt <= now)
+            .map(|lane| {
+                lane.locally_throttled = false;
+                lane.targets.clone()
+            })
+            .collect();
+        if released.is_empty() || self.spool.queue_len(NotificationPriority::Status) < 2 {
+            return;
         }
+
+        let default_targets = self.router.targets(NotificationPriority::Status).cloned();
+        self.spool.coalesce(
+            NotificationPriority::Status,
+            |entry| entry.targets.as_ref().or(default_targets.as_ref())
+                .is_some_and(|targets| targets.lanes().iter().any(|(_, lane)| released.contains(lane))),
+            |notifications| Self::digest(notifications, "suppressed by rate limiting"));
+    }
+
+    /// Schedule a delivery attempt no later than `time`.
+    fn schedule(&mut self, time: Instant) {
+        self.next_attempt = Some(self.next_attempt.map_or(time, |next_attempt| next_attempt.min(time)));
     }
 
-    /// Send an alarm message to the alarm and status notification targets.
-    pub fn send_alarm<T: ToString> (&self, message: T) {
-        if let Err(err) = self.alarm_sender.send(message.to_string()) {
-            log::error!("Failed to send alarm message '{}', notification manager is stopped", err.0);
+    /// Merge status notifications that were held back into a single
+    /// digest notification, `reason` describes why they were held back.
+    fn digest(notifications: Vec<Notification>, reason: &str) -> Notification {
+        // Maximum number of status lines included in the digest.
+        const MAX_DIGEST_LINES: usize = 10;
+
+        // Flatten earlier digests so a digest that is itself held back
+        // isn't nested in the next one.
+        let lines: Vec<String> = notifications.into_iter()
+            .flat_map(|notification| match notification.digest.is_empty() {
+                true => vec![notification.text],
+                false => notification.digest,
+            })
+            .collect();
+
+        let suppressed = lines.len();
+        let mut text = format!("{} status updates {}", suppressed, reason);
+        if suppressed > MAX_DIGEST_LINES {
+            text.push_str(&format!(", latest {}:", MAX_DIGEST_LINES));
+        } else {
+            text.push(':');
         }
+        for line in lines.iter().skip(suppressed.saturating_sub(MAX_DIGEST_LINES)) {
+            text.push('\n');
+            text.push_str(line);
+        }
+
+        let mut digest = Notification::system(text, StatusLevel::Status);
+        digest.digest = lines;
+        digest
     }
 }
 
+/// Send a notification to a list of targets according to their
+/// delivery policy.
+/// 
+/// Targets without their own message template use `template`, if set.
+/// For fan-out delivery, targets whose index is in `delivered_targets`
+/// are skipped and the index of each target the notification is
+/// delivered to is added, so that a retry only goes to the targets that
+/// failed. Returns an error if the notification was not delivered to
+/// every target (fan-out) or to any target (failover), or a
+/// `RateLimited` error if delivery was held back by rate limiting. With
+/// failover delivery, that is only if every target is rate limited.
+pub async fn send_notification_targets(
+    targets: &NotificationTargets,
+    notification: &Notification,
+    template: Option<&str>,
+    delivered_targets: &mut Vec<usize>,
+    rate_limiter: &mut RateLimiter)
+ -> anyhow::Result<()>
+{
+    let mut errors = vec![];
+    let mut rate_limited: Option<RateLimited> = None;
+
+    match targets.policy() {
+        DeliveryPolicy::FanOut => {
+            for (index, target) in targets.targets().iter().enumerate() {
+                if delivered_targets.contains(&index) {
+                    continue;
+                }
+                if let Err(target_rate_limited) = rate_limiter.try_take(&target.target) {
+                    if rate_limited.as_ref().is_none_or(|rate_limited| target_rate_limited.until > rate_limited.until) {
+                        rate_limited = Some(target_rate_limited);
+                    }
+                    continue;
+                }
+                match target.send(notification, template).await {
+                    Ok(_) => delivered_targets.push(index),
+                    Err(err) => match err.downcast::<RateLimited>() {
+                        Ok(target_rate_limited) => {
+                            rate_limiter.block(&target.target, target_rate_limited.until);
+                            if rate_limited.as_ref().is_none_or(|rate_limited| target_rate_limited.until > rate_limited.until) {
+                                rate_limited = Some(target_rate_limited);
+                            }
+                        },
+                        Err(err) if targets.targets().len() == 1 => errors.push(err.to_string()),
+                        Err(err) => errors.push(format!("target {}: {}", index, err)),
+                    },
+                }
+            }
+        },
+        DeliveryPolicy::Failover => {
+            // Rate limited targets are skipped, the notification is only
+            // held back if every target is rate limited, until the first
+            // one is available again.
+            for (index, target) in targets.targets().iter().enumerate() {
+                let target_rate_limited = match rate_limiter.try_take(&target.target) {
+                    Ok(()) => match target.send(notification, template).await {
+                        Ok(_) => return Ok(()),
+                        Err(err) => match err.downcast::<RateLimited>() {
+                            Ok(target_rate_limited) => {
+                                rate_limiter.block(&target.target, target_rate_limited.until);
+                                target_rate_limited
+                            },
+                            Err(err) => {
+                                log::warn!("Notification target {} failed, trying next target: {}", index, err);
+                                errors.push(format!("target {}: {}", index, err));
+                                continue;
+                            },
+                        },
+                    },
+                    Err(target_rate_limited) => target_rate_limited,
+                };
+                log::warn!("Notification target {} is {}, skipping it", index, target_rate_limited);
+                if rate_limited.as_ref().is_none_or(|rate_limited| target_rate_limited.until < rate_limited.until) {
+                    rate_limited = Some(target_rate_limited);
+                }
+            }
+        },
+    }
+
+    if !errors.is_empty() {
+        anyhow::bail!("{}", errors.join(", "))
+    }
+    if let Some(rate_limited) = rate_limited {
+        return Err(rate_limited.into());
+    }
+
+    Ok(())
+}
+
 /// Send a notification to a target.
-pub async fn send_notification(target: &NotificationTarget, message: &str) -> anyhow::Result<()> {
+pub async fn send_notification(target: &NotificationTarget, notification: &Notification) -> anyhow::Result<()> {
     match target {
-        NotificationTarget::DiscordWebhook { url, username } => {
-            let mut params = HashMap::new();
-            params.insert("content", message);
-            if let Some(username) = username {
-                params.insert("username", username);
+        NotificationTarget::DiscordWebhook { url, username, embeds, alarm_mention, .. } => {
+            let message = discord_message(notification, username.as_deref(), *embeds, alarm_mention.as_ref());
+            let client = reqwest::Client::new();
+            let resp = client.post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(message.to_string())
+                .send().await?;
+            if resp.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                let retry_after = discord_retry_after(resp).await;
+                return Err(RateLimited { until: Instant::now() + retry_after, by_target: true }.into());
+            }
+            resp.error_for_status()?;
+        },
+        NotificationTarget::Webhook { url, method, headers, body, .. } => {
+            let method = match method {
+                Some(method) => reqwest::Method::from_bytes(method.to_uppercase().as_bytes())?,
+                None => reqwest::Method::POST,
+            };
+            let client = reqwest::Client::new();
+            let mut request = client.request(method, url);
+            for (name, value) in headers.iter().flatten() {
+                request = request.header(name, value);
+            }
+            request = match body {
+                WebhookBody::Json(body_template) => {
+                    request
+                        .header(reqwest::header::CONTENT_TYPE, "application/json")
+                        .body(template::render(body_template, notification, template::escape_json))
+                },
+                WebhookBody::Form(field_templates) => {
+                    let fields: HashMap<&String, String> = field_templates.iter()
+                        .map(|(name, field_template)| (name, template::render(field_template, notification, template::escape_none)))
+                        .collect();
+                    request.form(&fields)
+                },
+            };
+            let resp = request.send().await?;
+            resp.error_for_status()?;
+        },
+        NotificationTarget::SlackWebhook { url, alarm_mention, .. } => {
+            let body = slack_message(notification, alarm_mention.as_deref());
+            let client = reqwest::Client::new();
+            let resp = client.post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.to_string())
+                .send().await?;
+            resp.error_for_status()?;
+        },
+        NotificationTarget::Telegram { bot_token, chat_id, api_base, .. } => {
+            let api_base = api_base.as_deref().unwrap_or("https://api.telegram.org").trim_end_matches('/');
+            let url = format!("{}/bot{}/sendMessage", api_base, bot_token);
+
+            // Status updates are delivered silently, only warnings and alarms make a sound.
+            let disable_notification = matches!(notification.level, StatusLevel::Info | StatusLevel::Status);
+            let body = serde_json::json!({
+                "chat_id": chat_id,
+                "text": notification.text,
+                "disable_notification": disable_notification,
+            });
+
+            // Strip the URL from errors to keep the bot token out of the logs.
+            let client = reqwest::Client::new();
+            let resp = client.post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.to_string())
+                .send().await
+                .map_err(reqwest::Error::without_url)?;
+            resp.error_for_status().map_err(reqwest::Error::without_url)?;
+        },
+        NotificationTarget::Email { host, port, security, username, password, from, to, .. } => {
+            let mut transport = match security {
+                SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+                SmtpSecurity::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?,
+                SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(host)?,
+            };
+            if let Some(port) = port {
+                transport = transport.port(*port);
+            }
+            if let (Some(username), Some(password)) = (username, password) {
+                transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+            }
+
+            let subject = match notification.level {
+                StatusLevel::Alarm => format!("Cerberus ALARM: {}", notification.device_name),
+                StatusLevel::Critical => format!("Cerberus CRITICAL ALARM: {}", notification.device_name),
+                level => format!("Cerberus {:?}: {}", level, notification.device_name),
+            };
+            let body = format!("{}\n\n{}\n", notification.text, notification.timestamp.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S %Z"));
+
+            let mut message = Message::builder()
+                .from(from.parse()?)
+                .subject(subject)
+                .header(ContentType::TEXT_PLAIN);
+            for to in to {
+                message = message.to(to.parse()?);
+            }
+
+            transport.build().send(message.body(body)?).await?;
+        },
+        NotificationTarget::Ntfy { topic, access_token, base_url, .. } => {
+            let base_url = base_url.as_deref().unwrap_or("https://ntfy.sh").trim_end_matches('/');
+            let (priority, tags) = match notification.level {
+                StatusLevel::Info => ("low", "information_source"),
+                StatusLevel::Status => ("default", "house"),
+                StatusLevel::Warning => ("high", "warning"),
+                StatusLevel::Alarm => ("max", "rotating_light"),
+                StatusLevel::Critical => ("max", "rotating_light,sos"),
+            };
+
+            let client = reqwest::Client::new();
+            let mut request = client.post(format!("{}/{}", base_url, topic))
+                .header("Title", format!("Cerberus: {}", notification.device_name))
+                .header("Priority", priority)
+                .header("Tags", tags)
+                .body(notification.message.clone());
+            if let Some(access_token) = access_token {
+                request = request.bearer_auth(access_token);
+            }
+            if let Some(ack_url) = &notification.ack_url {
+                request = request.header("Actions", format!("view, Acknowledge, {}", ack_url));
+            }
+            let resp = request.send().await?;
+            resp.error_for_status()?;
+        },
+        NotificationTarget::Gotify { base_url, app_token, .. } => {
+            // Gotify clients treat priority 8 and above as high importance.
+            let priority = match notification.level {
+                StatusLevel::Info => 1,
+                StatusLevel::Status => 4,
+                StatusLevel::Warning => 8,
+                StatusLevel::Alarm | StatusLevel::Critical => 10,
+            };
+            let mut body = serde_json::json!({
+                "title": format!("Cerberus: {}", notification.device_name),
+                "message": notification.message,
+                "priority": priority,
+            });
+            if let Some(ack_url) = &notification.ack_url {
+                body["message"] = format!("{}\nAcknowledge: {}", notification.message, ack_url).into();
+                body["extras"] = serde_json::json!({ "client::notification": { "click": { "url": ack_url } } });
             }
+
             let client = reqwest::Client::new();
-            let resp = client.post(url).form(&params).send().await?;
+            let resp = client.post(format!("{}/message", base_url.trim_end_matches('/')))
+                .header("X-Gotify-Key", app_token)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.to_string())
+                .send().await?;
             resp.error_for_status()?;
         },
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Build a Discord webhook message for a notification.
+/// 
+/// Mentions are only included for alarms, and `allowed_mentions` is set
+/// so that only those mentions ping, never text from the notification.
+fn discord_message(
+    notification: &Notification,
+    username: Option<&str>,
+    embeds: bool,
+    alarm_mention: Option<&DiscordMention>)
+ -> serde_json::Value
+{
+    let alarm_mention = match notification.level {
+        StatusLevel::Alarm | StatusLevel::Critical => alarm_mention.cloned().unwrap_or_default(),
+        _ => DiscordMention::default(),
+    };
+    let mention = alarm_mention.roles.iter().map(|role| format!("<@&{}>", role))
+        .chain(alarm_mention.users.iter().map(|user| format!("<@{}>", user)))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let mut message = serde_json::json!({
+        "allowed_mentions": {
+            "parse": [],
+            "roles": alarm_mention.roles,
+            "users": alarm_mention.users,
+        },
+    });
+
+    if embeds {
+        // Discord rejects empty embed field values and values longer
+        // than 1024 characters.
+        let field_value = |value: &str| match value.chars().count() {
+            0 => "-".to_string(),
+            1..=1024 => value.to_string(),
+            _ => format!("{}…", value.chars().take(1023).collect::<String>()),
+        };
+        let color = match notification.level {
+            StatusLevel::Info => 0x9e9e9e,
+            StatusLevel::Status => 0x2eb886,
+            StatusLevel::Warning => 0xdaa038,
+            StatusLevel::Alarm => 0xa30200,
+            StatusLevel::Critical => 0x6a0dad,
+        };
+        if !mention.is_empty() {
+            message["content"] = mention.into();
+        }
+        message["embeds"] = serde_json::json!([{
+            "title": format!("{:?}", notification.level),
+            "color": color,
+            "fields": [
+                { "name": "Device", "value": field_value(&notification.device_name), "inline": true },
+                { "name": "Message", "value": field_value(&notification.message) },
+            ],
+            "timestamp": notification.timestamp.to_rfc3339(),
+        }]);
+        if let Some(ack_url) = &notification.ack_url {
+            message["embeds"][0]["url"] = ack_url.clone().into();
+            message["embeds"][0]["fields"].as_array_mut().expect("fields is an array")
+                .push(serde_json::json!({ "name": "Acknowledge", "value": format!("[Acknowledge alarm]({})", ack_url) }));
+        }
+    } else if mention.is_empty() {
+        message["content"] = notification.text.clone().into();
+    } else {
+        message["content"] = format!("{} {}", mention, notification.text).into();
+    }
+
+    if let Some(username) = username {
+        message["username"] = username.into();
+    }
+
+    message
+}
+
+/// Get the time to wait before retrying from a Discord rate limit
+/// response.
+async fn discord_retry_after(resp: reqwest::Response) -> Duration {
+    let headers = resp.headers().clone();
+    let body = resp.bytes().await.unwrap_or_default();
+    discord_retry_after_from(&headers, &body)
+}
+
+/// Get the time to wait before retrying from the headers and body of a
+/// Discord rate limit response.
+/// 
+/// Uses the longest of the `Retry-After` and `X-RateLimit-Reset-After`
+/// headers and the `retry_after` field of the JSON body.
+fn discord_retry_after_from(headers: &reqwest::header::HeaderMap, body: &[u8]) -> Duration {
+    // Wait used if the response doesn't say how long to wait.
+    const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+    // Upper bound on waits, in case of a bogus response.
+    const MAX_RETRY_AFTER: Duration = Duration::from_secs(60 * 60);
+
+    let header_seconds = |name: &str| {
+        headers.get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<f64>().ok())
+    };
+    let retry_after_header = header_seconds("retry-after");
+    let reset_after_header = header_seconds("x-ratelimit-reset-after");
+
+    let body_retry_after = serde_json::from_slice::<serde_json::Value>(body).ok()
+        .and_then(|body| body.get("retry_after").and_then(serde_json::Value::as_f64));
+
+    // Clamp before converting, huge values don't fit in a Duration.
+    [retry_after_header, reset_after_header, body_retry_after].into_iter()
+        .flatten()
+        .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+        .map(|seconds| Duration::from_secs_f64(seconds.min(MAX_RETRY_AFTER.as_secs_f64())))
+        .max()
+        .unwrap_or(DEFAULT_RETRY_AFTER)
+}
+
+/// Build a Slack Block Kit message for a notification.
+/// 
+/// The message is sent as an attachment colored by the status level, the
+/// mention is only included for alarms.
+fn slack_message(notification: &Notification, alarm_mention: Option<&str>) -> serde_json::Value {
+    // Slack requires &, < and > to be escaped in message text.
+    let escape = |text: &str| text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+
+    let color = match notification.level {
+        StatusLevel::Info => "#9e9e9e",
+        StatusLevel::Status => "#2eb886",
+        StatusLevel::Warning => "#daa038",
+        StatusLevel::Alarm => "#a30200",
+        StatusLevel::Critical => "#6a0dad",
+    };
+
+    let mention = match (notification.level, alarm_mention) {
+        (StatusLevel::Alarm | StatusLevel::Critical, Some(alarm_mention)) => format!("{} ", alarm_mention),
+        _ => String::new(),
+    };
+    let text = format!("{}*{}*: {}", mention, escape(&notification.device_name), escape(&notification.message));
+
+    let context = format!("{:?} | <!date^{}^{{date_short_pretty}} {{time_secs}}|{}>",
+        notification.level, notification.timestamp.timestamp(), notification.timestamp.to_rfc3339());
+
+    let mut blocks = vec![
+        serde_json::json!({
+            "type": "section",
+            "text": { "type": "mrkdwn", "text": text },
+        }),
+        serde_json::json!({
+            "type": "context",
+            "elements": [{ "type": "mrkdwn", "text": context }],
+        }),
+    ];
+    if let Some(ack_url) = &notification.ack_url {
+        blocks.push(serde_json::json!({
+            "type": "actions",
+            "elements": [{
+                "type": "button",
+                "text": { "type": "plain_text", "text": "Acknowledge" },
+                "url": ack_url,
+                "style": "danger",
+            }],
+        }));
+    }
+
+    serde_json::json!({
+        "text": format!("{}{}", mention, escape(&notification.text)),
+        "attachments": [{
+            "color": color,
+            "blocks": blocks,
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use reqwest::header::{HeaderMap, HeaderValue};
+    use warp::Filter;
+
+    use super::*;
+
+    /// Number of requests received by the mock server for each path.
+    type RequestCounts = Arc<std::sync::Mutex<HashMap<String, usize>>>;
+
+    /// Start a mock webhook server. Paths starting with `down` fail,
+    /// paths starting with `limited` are rate limited and all other
+    /// paths succeed.
+    fn mock_server() -> (SocketAddr, RequestCounts) {
+        let requests: RequestCounts = Default::default();
+        let counts = requests.clone();
+        let route = warp::post()
+            .and(warp::path::param::<String>())
+            .map(move |name: String| {
+                *counts.lock().unwrap().entry(name.clone()).or_default() += 1;
+                let status = match name {
+                    name if name.starts_with("down") => warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                    name if name.starts_with("limited") => warp::http::StatusCode::TOO_MANY_REQUESTS,
+                    _ => warp::http::StatusCode::NO_CONTENT,
+                };
+                warp::reply::with_header(warp::reply::with_status(warp::reply(), status), "retry-after", "60")
+            });
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (address, requests)
+    }
+
+    /// Discord webhook targets on the mock server with the given paths.
+    fn mock_targets(address: SocketAddr, paths: &[&str], policy: DeliveryPolicy) -> NotificationTargets {
+        let targets = paths.iter()
+            .map(|path| serde_json::from_value(serde_json::json!({
+                "DiscordWebhook": { "url": format!("http://{}/{}", address, path), "username": null, "alarm_mention": null },
+            })).unwrap())
+            .collect();
+        NotificationTargets::List { targets, policy }
+    }
+
+    /// Number of requests the mock server received for a path.
+    fn request_count(requests: &RequestCounts, path: &str) -> usize {
+        requests.lock().unwrap().get(path).copied().unwrap_or_default()
+    }
+
+    /// Build response headers from name and value pairs.
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn discord_retry_after_uses_longest_wait() {
+        let headers = headers(&[("retry-after", "2"), ("x-ratelimit-reset-after", "3.5")]);
+        assert_eq!(discord_retry_after_from(&headers, b""), Duration::from_secs_f64(3.5));
+        assert_eq!(discord_retry_after_from(&headers, br#"{"retry_after": 7.25}"#), Duration::from_secs_f64(7.25));
+    }
+
+    #[test]
+    fn discord_retry_after_defaults() {
+        assert_eq!(discord_retry_after_from(&HeaderMap::new(), b"not json"), Duration::from_secs(5));
+
+        // Negative and unparseable values are ignored.
+        let headers = headers(&[("retry-after", "-1"), ("x-ratelimit-reset-after", "soon")]);
+        assert_eq!(discord_retry_after_from(&headers, br#"{"retry_after": "1"}"#), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn discord_retry_after_clamps_bogus_values() {
+        let max = Duration::from_secs(60 * 60);
+        assert_eq!(discord_retry_after_from(&headers(&[("retry-after", "1e20")]), b""), max);
+        assert_eq!(discord_retry_after_from(&HeaderMap::new(), br#"{"retry_after": 1e300}"#), max);
+        assert_eq!(discord_retry_after_from(&headers(&[("retry-after", "inf")]), b""), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn target_template_is_parsed_beside_the_target() {
+        let targets: NotificationTargets = serde_json::from_str(r#"{
+            "DiscordWebhook": { "url": "https://example.com/webhook", "username": null },
+            "template": "{level}: {message}"
+        }"#).unwrap();
+
+        let target = &targets.targets()[0];
+        assert!(matches!(&target.target, NotificationTarget::DiscordWebhook { url, .. } if url == "https://example.com/webhook"));
+        assert_eq!(target.template.as_deref(), Some("{level}: {message}"));
+    }
+
+    #[test]
+    fn target_template_includes_ack_url_once() {
+        let target = |template: &str| NotificationTargetConfig {
+            target: NotificationTarget::Gotify { base_url: "https://example.com".to_string(), app_token: "token".to_string() },
+            template: Some(template.to_string()),
+        };
+        let mut notification = Notification::system("Intruder", StatusLevel::Alarm);
+        notification.ack_url = Some("https://example.com/ack/1".to_string());
+
+        let templated = target("{message}").apply_template(&notification, None).unwrap();
+        assert_eq!(templated.text, "Intruder\nAcknowledge: https://example.com/ack/1");
+        assert_eq!(templated.message, "Intruder");
+
+        let templated = target("{message} ({ack_url})").apply_template(&notification, None).unwrap();
+        assert_eq!(templated.text, "Intruder (https://example.com/ack/1)");
+    }
+
+    #[test]
+    fn global_template_applies_to_targets_without_their_own() {
+        let target = |target: serde_json::Value| serde_json::from_value::<NotificationTargetConfig>(target).unwrap();
+        let slack = target(serde_json::json!({ "SlackWebhook": { "url": "https://example.com/slack", "alarm_mention": null } }));
+        let ntfy = target(serde_json::json!({ "Ntfy": { "topic": "alarms", "access_token": null, "base_url": null }, "template": "{message}!" }));
+        let telegram = target(serde_json::json!({ "Telegram": { "bot_token": "token", "chat_id": "1", "api_base": null } }));
+        let notification = Notification::system("Armed", StatusLevel::Status);
+
+        let templated = slack.apply_template(&notification, Some("{device_name}: {message}")).unwrap();
+        assert_eq!(templated.text, "Cerberus: Armed");
+        assert_eq!(templated.message, "Cerberus: Armed");
+
+        let templated = ntfy.apply_template(&notification, Some("{device_name}: {message}")).unwrap();
+        assert_eq!(templated.message, "Armed!");
+
+        // Text-based targets keep the status message for their own use.
+        let templated = telegram.apply_template(&notification, Some("{device_name}: {message}")).unwrap();
+        assert_eq!(templated.text, "Cerberus: Armed");
+        assert_eq!(templated.message, "Armed");
+
+        assert!(telegram.apply_template(&notification, None).is_none());
+    }
+
+    #[test]
+    fn slack_message_escapes_text_and_mentions_only_alarms() {
+        let mut notification = Notification::system("Door <open> & unlocked", StatusLevel::Alarm);
+        notification.device_name = "Front & Back".to_string();
+
+        let message = slack_message(&notification, Some("<!channel>"));
+        assert_eq!(message["text"], "<!channel> Door &lt;open&gt; &amp; unlocked");
+        assert_eq!(message["attachments"][0]["color"], "#a30200");
+        assert_eq!(message["attachments"][0]["blocks"][0]["text"]["text"], "<!channel> *Front &amp; Back*: Door &lt;open&gt; &amp; unlocked");
+
+        notification.level = StatusLevel::Status;
+        let message = slack_message(&notification, Some("<!channel>"));
+        assert_eq!(message["text"], "Door &lt;open&gt; &amp; unlocked");
+        assert_eq!(message["attachments"][0]["color"], "#2eb886");
+    }
+
+    #[test]
+    fn slack_message_adds_ack_button() {
+        let mut notification = Notification::system("Intruder", StatusLevel::Alarm);
+        assert_eq!(slack_message(&notification, None)["attachments"][0]["blocks"].as_array().unwrap().len(), 2);
+
+        notification.ack_url = Some("https://example.com/ack/1".to_string());
+        let message = slack_message(&notification, None);
+        assert_eq!(message["attachments"][0]["blocks"][2]["elements"][0]["url"], "https://example.com/ack/1");
+    }
+
+    #[test]
+    fn discord_message_mentions_only_alarms() {
+        let mention = DiscordMention { roles: vec!["1".to_string()], users: vec!["2".to_string()] };
+        let notification = Notification::system("Intruder <@3>", StatusLevel::Alarm);
+
+        let message = discord_message(&notification, Some("Cerberus"), false, Some(&mention));
+        assert_eq!(message["content"], "<@&1> <@2> Intruder <@3>");
+        assert_eq!(message["username"], "Cerberus");
+        assert_eq!(message["allowed_mentions"], serde_json::json!({ "parse": [], "roles": ["1"], "users": ["2"] }));
+
+        let notification = Notification::system("Armed <@3>", StatusLevel::Status);
+        let message = discord_message(&notification, None, false, Some(&mention));
+        assert_eq!(message["content"], "Armed <@3>");
+        assert!(message.get("username").is_none());
+        assert_eq!(message["allowed_mentions"], serde_json::json!({ "parse": [], "roles": [], "users": [] }));
+    }
+
+    #[test]
+    fn discord_embed_fields_fit_discord_limits() {
+        let mut notification = Notification::system("x".repeat(2000), StatusLevel::Critical);
+        notification.device_name = String::new();
+        notification.ack_url = Some("https://example.com/ack/1".to_string());
+
+        let message = discord_message(&notification, None, true, None);
+        let embed = &message["embeds"][0];
+        assert!(message.get("content").is_none());
+        assert_eq!(embed["color"], 0x6a0dad);
+        assert_eq!(embed["url"], "https://example.com/ack/1");
+        assert_eq!(embed["fields"][0]["value"], "-");
+        assert_eq!(embed["fields"][1]["value"].as_str().unwrap().chars().count(), 1024);
+        assert_eq!(embed["fields"][2]["value"], "[Acknowledge alarm](https://example.com/ack/1)");
+    }
+
+    #[tokio::test]
+    async fn fan_out_retries_only_failed_targets() {
+        let (address, requests) = mock_server();
+        let targets = mock_targets(address, &["ok", "down", "limited", "ok2"], DeliveryPolicy::FanOut);
+        let notification = Notification::system("Intruder", StatusLevel::Alarm);
+        let mut rate_limiter = RateLimiter::new(None);
+
+        let mut delivered_targets = vec![];
+        let err = send_notification_targets(&targets, &notification, None, &mut delivered_targets, &mut rate_limiter).await.unwrap_err();
+        assert!(err.to_string().starts_with("target 1: "));
+        assert_eq!(delivered_targets, [0, 3]);
+
+        // The rate limited target is skipped until its rate limit ends.
+        let err = send_notification_targets(&targets, &notification, None, &mut delivered_targets, &mut rate_limiter).await.unwrap_err();
+        assert!(err.to_string().starts_with("target 1: "));
+        assert_eq!(delivered_targets, [0, 3]);
+        assert_eq!(request_count(&requests, "ok"), 1);
+        assert_eq!(request_count(&requests, "down"), 2);
+        assert_eq!(request_count(&requests, "limited"), 1);
+        assert_eq!(request_count(&requests, "ok2"), 1);
+
+        let targets = mock_targets(address, &["ok", "limited"], DeliveryPolicy::FanOut);
+        let err = send_notification_targets(&targets, &notification, None, &mut vec![0], &mut rate_limiter).await.unwrap_err();
+        assert!(err.downcast_ref::<RateLimited>().is_some_and(|rate_limited| rate_limited.by_target));
+        assert_eq!(request_count(&requests, "ok"), 1);
+    }
+
+    #[tokio::test]
+    async fn failover_tries_targets_in_order() {
+        let (address, requests) = mock_server();
+        let notification = Notification::system("Intruder", StatusLevel::Alarm);
+        let mut rate_limiter = RateLimiter::new(None);
+
+        let targets = mock_targets(address, &["down", "ok", "ok2"], DeliveryPolicy::Failover);
+        send_notification_targets(&targets, &notification, None, &mut vec![], &mut rate_limiter).await.unwrap();
+        assert_eq!(request_count(&requests, "down"), 1);
+        assert_eq!(request_count(&requests, "ok"), 1);
+        assert_eq!(request_count(&requests, "ok2"), 0);
+
+        // Rate limited targets are skipped like failed ones.
+        let targets = mock_targets(address, &["limited", "ok2"], DeliveryPolicy::Failover);
+        send_notification_targets(&targets, &notification, None, &mut vec![], &mut rate_limiter).await.unwrap();
+        assert_eq!(request_count(&requests, "ok2"), 1);
+
+        let targets = mock_targets(address, &["down", "down2"], DeliveryPolicy::Failover);
+        let err = send_notification_targets(&targets, &notification, None, &mut vec![], &mut rate_limiter).await.unwrap_err();
+        assert!(err.to_string().starts_with("target 0: "));
+        assert!(err.to_string().contains(", target 1: "));
+    }
+
+    #[tokio::test]
+    async fn webhook_form_fields_are_rendered_and_encoded() {
+        let received: Arc<std::sync::Mutex<Option<HashMap<String, String>>>> = Default::default();
+        let form = received.clone();
+        let route = warp::post()
+            .and(warp::body::form())
+            .map(move |fields: HashMap<String, String>| {
+                *form.lock().unwrap() = Some(fields);
+                warp::reply()
+            });
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let target: NotificationTarget = serde_json::from_value(serde_json::json!({
+            "Webhook": {
+                "url": format!("http://{}/", address),
+                "body": { "Form": { "title": "{device_name} & {level}", "body": "{message} {unknown}" } },
+            },
+        })).unwrap();
+        let notification = Notification::system("Door \"A\"\nopen=1", StatusLevel::Alarm);
+        send_notification(&target, &notification).await.unwrap();
+
+        let fields = received.lock().unwrap().take().unwrap();
+        assert_eq!(fields["title"], "Cerberus & Alarm");
+        assert_eq!(fields["body"], "Door \"A\"\nopen=1 {unknown}");
+    }
+
+    /// Email target from JSON fields, sending to `port` on localhost.
+    fn email_target(port: u16, from: &str, to: &[&str]) -> serde_json::Result<NotificationTarget> {
+        serde_json::from_value(serde_json::json!({
+            "Email": { "host": "127.0.0.1", "port": port, "security": "None", "from": from, "to": to },
+        }))
+    }
+
+    /// Start a minimal SMTP server that accepts one message and returns
+    /// the message data.
+    async fn smtp_sink() -> (u16, tokio::task::JoinHandle<String>) {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let sink = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            let mut data = String::new();
+            writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let reply: &[u8] = match line.to_uppercase() {
+                    command if command.starts_with("EHLO") => b"250 localhost\r\n",
+                    command if command.starts_with("DATA") => {
+                        writer.write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n").await.unwrap();
+                        while let Some(line) = lines.next_line().await.unwrap() {
+                            if line == "." {
+                                break;
+                            }
+                            data.push_str(&line);
+                            data.push('\n');
+                        }
+                        b"250 OK\r\n"
+                    },
+                    command if command.starts_with("QUIT") => {
+                        writer.write_all(b"221 Bye\r\n").await.unwrap();
+                        break;
+                    },
+                    _ => b"250 OK\r\n",
+                };
+                writer.write_all(reply).await.unwrap();
+            }
+            data
+        });
+        (port, sink)
+    }
+
+    #[test]
+    fn email_addresses_are_validated_when_loaded() {
+        assert!(email_target(25, "Cerberus <cerberus@example.com>", &["alice@example.com", "bob@example.com"]).is_ok());
+        assert!(email_target(25, "cerberus@example.com", &[]).unwrap_err().to_string().contains("at least one recipient"));
+        assert!(email_target(25, "cerberus", &["alice@example.com"]).unwrap_err().to_string().contains("invalid email address 'cerberus'"));
+        assert!(email_target(25, "cerberus@example.com", &["alice@example.com", "bob@"]).unwrap_err().to_string().contains("invalid email address 'bob@'"));
+    }
+
+    #[tokio::test]
+    async fn email_is_sent_to_smtp_server() {
+        let (port, sink) = smtp_sink().await;
+        let target = email_target(port, "cerberus@example.com", &["alice@example.com"]).unwrap();
+        let notification = Notification::system("Intruder", StatusLevel::Alarm);
+        send_notification(&target, &notification).await.unwrap();
+
+        let data = sink.await.unwrap();
+        assert!(data.contains("From: cerberus@example.com"));
+        assert!(data.contains("To: alice@example.com"));
+        assert!(data.contains("Subject: Cerberus ALARM: Cerberus"));
+        assert!(data.contains("Intruder"));
+    }
+
+    /// Notification worker sending alarms to `alarm_targets`.
+    fn worker(status_targets: Option<NotificationTargets>, alarm_targets: NotificationTargets) -> NotificationWorker {
+        NotificationWorker {
+            router: Arc::new(NotificationRouter::new(status_targets, Some(alarm_targets), Default::default(), vec![])),
+            heartbeat: None,
+            spool: NotificationSpool::in_memory(),
+            pending_count: Default::default(),
+            rate_limited_count: Default::default(),
+            last_notification: Instant::now(),
+            rate_limiter: RateLimiter::new(None),
+            lanes: vec![],
+            next_attempt: None,
+            quiet_hours: None,
+            template: None,
+        }
+    }
+
+    /// Queue alarms with the given messages and attempt to deliver them.
+    async fn send_alarms(worker: &mut NotificationWorker, messages: &[&str]) {
+        for message in messages {
+            let targets = worker.router.targets(NotificationPriority::Alarm).cloned().unwrap();
+            worker.queue(NotificationPriority::Alarm, Notification::system(message, StatusLevel::Alarm), targets);
+            worker.deliver().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_target_does_not_hold_back_other_targets() {
+        let (address, requests) = mock_server();
+        let mut worker = worker(None, mock_targets(address, &["down", "ok"], DeliveryPolicy::FanOut));
+        send_alarms(&mut worker, &["one", "two"]).await;
+
+        // The second alarm waits for the failed target's retry, but is
+        // delivered to the healthy target right away.
+        assert_eq!(request_count(&requests, "down"), 1);
+        assert_eq!(request_count(&requests, "ok"), 2);
+        assert!(worker.next_attempt.is_some());
+        for id in worker.spool.queued_ids(NotificationPriority::Alarm) {
+            assert_eq!(worker.spool.get(NotificationPriority::Alarm, id).unwrap().delivered_targets, [1]);
+        }
+        assert_eq!(worker.spool.queue_len(NotificationPriority::Alarm), 2);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_target_does_not_hold_back_other_targets() {
+        let (address, requests) = mock_server();
+        let mut worker = worker(None, mock_targets(address, &["limited", "ok"], DeliveryPolicy::FanOut));
+        send_alarms(&mut worker, &["one", "two"]).await;
+
+        assert_eq!(request_count(&requests, "limited"), 1);
+        assert_eq!(request_count(&requests, "ok"), 2);
+        assert_eq!(worker.spool.queue_len(NotificationPriority::Alarm), 2);
+
+        // Alarms routed to other targets aren't held back either.
+        let targets = mock_targets(address, &["ok2"], DeliveryPolicy::FanOut);
+        worker.queue(NotificationPriority::Alarm, Notification::system("three", StatusLevel::Alarm), targets);
+        worker.deliver().await;
+        assert_eq!(request_count(&requests, "ok2"), 1);
+        assert_eq!(request_count(&requests, "limited"), 1);
+    }
+
+    #[tokio::test]
+    async fn locally_throttled_statuses_are_merged_into_a_digest() {
+        let (address, requests) = mock_server();
+        let status_targets = mock_targets(address, &["ok2"], DeliveryPolicy::FanOut);
+        let mut worker = worker(Some(status_targets.clone()), mock_targets(address, &["ok"], DeliveryPolicy::FanOut));
+        worker.rate_limiter = RateLimiter::new(Some(RateLimitConfig { burst: 1, per_minute: 1 }));
+        for message in ["one", "two", "three"] {
+            worker.queue(NotificationPriority::Status, Notification::system(message, StatusLevel::Status), status_targets.clone());
+            worker.deliver().await;
+        }
+        assert_eq!(request_count(&requests, "ok2"), 1);
+        assert_eq!(worker.spool.queue_len(NotificationPriority::Status), 2);
+
+        // Once the lane is released the held statuses are merged, alarms
+        // to other targets are unaffected meanwhile.
+        send_alarms(&mut worker, &["alarm"]).await;
+        assert_eq!(request_count(&requests, "ok"), 1);
+        worker.lanes[0].next_attempt = Instant::now();
+        worker.deliver().await;
+        let ids = worker.spool.queued_ids(NotificationPriority::Status);
+        assert_eq!(ids.len(), 1);
+        let digest = &worker.spool.get(NotificationPriority::Status, ids[0]).unwrap().notification;
+        assert_eq!(digest.digest, ["two", "three"]);
+    }
+}
@@ -0,0 +1,239 @@
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::{Serialize, Deserialize};
+
+use crate::status::StatusLevel;
+
+/// Quiet hours configuration for status notifications.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct QuietHoursConfig {
+    /// IANA time zone the quiet hours are in, e.g. `America/Los_Angeles`.
+    /// Defaults to the system time zone.
+    pub timezone: Option<Tz>,
+
+    /// Quiet hours rules, a notification is quiet if any rule matches it.
+    pub rules: Vec<QuietHoursRule>,
+}
+
+/// Quiet hours rule, a time window on some days of the week.
+/// 
+/// Windows that end before they start run overnight, `22:00` to `07:00`
+/// on Friday covers Friday night until Saturday morning. A window that
+/// ends when it starts covers the whole day.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct QuietHoursRule {
+    /// Days the window starts on, defaults to every day.
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+
+    /// Start of the window, e.g. `22:00`.
+    pub start: NaiveTime,
+
+    /// End of the window, e.g. `07:00`.
+    pub end: NaiveTime,
+
+    /// Status levels the rule applies to, defaults to `Info` and
+    /// `Status`. Warnings and alarms are never held.
+    #[serde(default = "QuietHoursRule::default_levels")]
+    pub levels: Vec<StatusLevel>,
+
+    /// What to do with notifications during the window.
+    #[serde(default)]
+    pub action: QuietHoursAction,
+}
+
+/// Action taken on notifications during quiet hours.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum QuietHoursAction {
+    /// Hold notifications and send them as a digest when quiet hours end.
+    #[default]
+    Hold,
+    /// Drop notifications.
+    Suppress,
+}
+
+impl QuietHoursRule {
+    /// Default levels a rule applies to.
+    fn default_levels() -> Vec<StatusLevel> {
+        vec![StatusLevel::Info, StatusLevel::Status]
+    }
+
+    /// Returns true if the rule's window covers a local time.
+    fn is_active(&self, local: NaiveDateTime) -> bool {
+        let on_day = |day: Weekday| self.days.is_empty() || self.days.contains(&day);
+        let time = local.time();
+        let today = local.weekday();
+        if self.start < self.end {
+            on_day(today) && time >= self.start && time < self.end
+        } else if self.start > self.end {
+            (on_day(today) && time >= self.start) || (on_day(today.pred()) && time < self.end)
+        } else {
+            on_day(today)
+        }
+    }
+}
+
+/// Quiet hours schedule.
+pub struct QuietHours {
+    /// Quiet hours configuration.
+    config: QuietHoursConfig,
+}
+
+impl QuietHours {
+    /// Number of days to look ahead for the end of quiet hours.
+    const RELEASE_LOOKAHEAD_DAYS: i64 = 8;
+
+    /// Create a quiet hours schedule.
+    pub fn new(config: QuietHoursConfig) -> Self {
+        for rule in &config.rules {
+            if rule.levels.iter().any(|level| *level >= StatusLevel::Warning) {
+                log::warn!("Quiet hours never apply to warnings and alarms, ignoring them in quiet hours rule");
+            }
+        }
+        Self { config }
+    }
+
+    /// Get the action to take for a notification of a level sent at
+    /// `now`, or None if it should be sent immediately.
+    /// 
+    /// Suppression takes priority over holding if several rules match.
+    pub fn action(&self, level: StatusLevel, now: DateTime<Utc>) -> Option<QuietHoursAction> {
+        if level >= StatusLevel::Warning {
+            return None;
+        }
+
+        let local = self.local_time(now);
+        self.config.rules.iter()
+            .filter(|rule| rule.levels.contains(&level) && rule.is_active(local))
+            .map(|rule| rule.action)
+            .max_by_key(|action| *action == QuietHoursAction::Suppress)
+    }
+
+    /// Get the time notifications of a level held at `now` can be
+    /// released, the first end of a window after which no rule applies.
+    /// 
+    /// If quiet hours don't end within a week, returns the last time
+    /// checked so they are checked again then.
+    pub fn release_time(&self, level: StatusLevel, now: DateTime<Utc>) -> DateTime<Utc> {
+        // Quiet hours can only end at the end of a window or at midnight,
+        // for windows covering whole days.
+        let today = self.local_time(now).date();
+        let mut ends: Vec<DateTime<Utc>> = (0..=Self::RELEASE_LOOKAHEAD_DAYS)
+            .filter_map(|days| today.checked_add_signed(chrono::Duration::days(days)))
+            .flat_map(|date| {
+                self.config.rules.iter()
+                    .map(move |rule| date.and_time(rule.end))
+                    .chain(date.and_hms_opt(0, 0, 0))
+            })
+            .filter_map(|local| self.utc_time(local))
+            .filter(|end| *end > now)
+            .collect();
+        ends.sort();
+
+        ends.iter().copied()
+            .find(|end| self.action(level, *end).is_none())
+            .or_else(|| ends.last().copied())
+            .unwrap_or(now + chrono::Duration::days(Self::RELEASE_LOOKAHEAD_DAYS))
+    }
+
+    /// Convert a time to the quiet hours time zone.
+    fn local_time(&self, time: DateTime<Utc>) -> NaiveDateTime {
+        match self.config.timezone {
+            Some(timezone) => time.with_timezone(&timezone).naive_local(),
+            None => time.with_timezone(&Local).naive_local(),
+        }
+    }
+
+    /// Convert a time in the quiet hours time zone to UTC, or None if the
+    /// time is skipped by a daylight saving time change.
+    fn utc_time(&self, local: NaiveDateTime) -> Option<DateTime<Utc>> {
+        match self.config.timezone {
+            Some(timezone) => timezone.from_local_datetime(&local).earliest().map(|time| time.with_timezone(&Utc)),
+            None => Local.from_local_datetime(&local).earliest().map(|time| time.with_timezone(&Utc)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Quiet hours in UTC with the given rules.
+    fn quiet_hours(rules: serde_json::Value) -> QuietHours {
+        QuietHours::new(serde_json::from_value(serde_json::json!({ "timezone": "UTC", "rules": rules })).unwrap())
+    }
+
+    /// Parse an RFC 3339 time.
+    fn time(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&Utc)
+    }
+
+    /// Parse a local time.
+    fn local(time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    /// Quiet hours rule from JSON.
+    fn rule(rule: serde_json::Value) -> QuietHoursRule {
+        serde_json::from_value(rule).unwrap()
+    }
+
+    #[test]
+    fn rule_covers_daytime_window() {
+        let rule = rule(serde_json::json!({ "days": ["Mon"], "start": "09:00:00", "end": "17:00:00" }));
+        // 2024-03-04 is a Monday.
+        assert!(rule.is_active(local("2024-03-04 09:00")));
+        assert!(rule.is_active(local("2024-03-04 16:59")));
+        assert!(!rule.is_active(local("2024-03-04 17:00")));
+        assert!(!rule.is_active(local("2024-03-04 08:59")));
+        assert!(!rule.is_active(local("2024-03-05 12:00")));
+    }
+
+    #[test]
+    fn rule_covers_overnight_window_from_its_start_day() {
+        let rule = rule(serde_json::json!({ "days": ["Fri"], "start": "22:00:00", "end": "07:00:00" }));
+        // 2024-03-01 is a Friday.
+        assert!(rule.is_active(local("2024-03-01 23:00")));
+        assert!(rule.is_active(local("2024-03-02 06:59")));
+        assert!(!rule.is_active(local("2024-03-02 07:00")));
+        assert!(!rule.is_active(local("2024-03-01 06:00")));
+        assert!(!rule.is_active(local("2024-03-02 23:00")));
+    }
+
+    #[test]
+    fn rule_with_equal_start_and_end_covers_whole_day() {
+        let rule = rule(serde_json::json!({ "days": ["Sat", "Sun"], "start": "12:00:00", "end": "12:00:00" }));
+        assert!(rule.is_active(local("2024-03-02 00:00")));
+        assert!(rule.is_active(local("2024-03-03 23:59")));
+        assert!(!rule.is_active(local("2024-03-04 00:00")));
+    }
+
+    #[test]
+    fn warnings_are_never_quiet() {
+        let quiet_hours = quiet_hours(serde_json::json!([
+            { "start": "00:00:00", "end": "00:00:00", "levels": ["Status", "Warning"], "action": "Suppress" },
+        ]));
+        let now = time("2024-03-01T12:00:00Z");
+        assert_eq!(quiet_hours.action(StatusLevel::Status, now), Some(QuietHoursAction::Suppress));
+        assert_eq!(quiet_hours.action(StatusLevel::Info, now), None);
+        assert_eq!(quiet_hours.action(StatusLevel::Warning, now), None);
+    }
+
+    #[test]
+    fn release_time_is_end_of_overnight_window() {
+        let quiet_hours = quiet_hours(serde_json::json!([{ "start": "22:00:00", "end": "07:00:00" }]));
+        let now = time("2024-03-01T23:30:00Z");
+        assert_eq!(quiet_hours.release_time(StatusLevel::Status, now), time("2024-03-02T07:00:00Z"));
+    }
+
+    #[test]
+    fn release_time_skips_adjoining_windows() {
+        let quiet_hours = quiet_hours(serde_json::json!([
+            { "days": ["Sat", "Sun"], "start": "00:00:00", "end": "00:00:00" },
+            { "start": "22:00:00", "end": "07:00:00" },
+        ]));
+        // Friday night runs into the weekend, which ends Monday morning.
+        let now = time("2024-03-01T23:30:00Z");
+        assert_eq!(quiet_hours.release_time(StatusLevel::Status, now), time("2024-03-04T07:00:00Z"));
+    }
+}
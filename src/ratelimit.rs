@@ -0,0 +1,181 @@
+use std::time::Duration;
+
+use serde::{Serialize, Deserialize};
+use tokio::time::Instant;
+
+use crate::notification::NotificationTarget;
+
+/// Notification rate limit configuration, applied to each target.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct RateLimitConfig {
+    /// Number of notifications that can be sent to a target in a burst.
+    pub burst: u32,
+
+    /// Sustained number of notifications per minute for a target.
+    pub per_minute: u32,
+}
+
+/// Error returned when a notification is held back by rate limiting.
+#[derive(Debug)]
+pub struct RateLimited {
+    /// Time a notification can be sent again.
+    pub until: Instant,
+
+    /// Whether the target itself asked us to wait, rather than the local
+    /// rate limit.
+    pub by_target: bool,
+}
+
+impl std::fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "rate limited for {:?}", self.until.saturating_duration_since(Instant::now()))
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+/// Token bucket for a single target.
+struct TokenBucket {
+    /// Available tokens.
+    tokens: f64,
+
+    /// Time tokens were last refilled.
+    last_refill: Instant,
+
+    /// Time the target asked us to wait until before sending again.
+    blocked_until: Option<Instant>,
+}
+
+/// Per-target token bucket rate limiter.
+/// 
+/// Also holds back notifications to targets that reject deliveries
+/// because of their own rate limits.
+pub struct RateLimiter {
+    /// Rate limit, or None if rate limiting is disabled.
+    config: Option<RateLimitConfig>,
+
+    /// Token bucket for each target that has been sent to.
+    buckets: Vec<(NotificationTarget, TokenBucket)>,
+
+    /// Number of delivery attempts rejected by a target's rate limit.
+    rejected_count: u64,
+}
+
+impl RateLimiter {
+    /// Create a new rate limiter, rate limiting is disabled if `config`
+    /// is None.
+    pub fn new(config: Option<RateLimitConfig>) -> Self {
+        Self {
+            config,
+            buckets: vec![],
+            rejected_count: 0,
+        }
+    }
+
+    /// Number of delivery attempts rejected by a target's own rate limit.
+    pub fn rejected_count(&self) -> u64 {
+        self.rejected_count
+    }
+
+    /// Record that a target rejected a delivery attempt because of its
+    /// own rate limit, holding back notifications to it until `until`.
+    pub fn block(&mut self, target: &NotificationTarget, until: Instant) {
+        self.rejected_count += 1;
+        let bucket = self.bucket(target);
+        if bucket.blocked_until.is_none_or(|blocked_until| until > blocked_until) {
+            bucket.blocked_until = Some(until);
+        }
+    }
+
+    /// Get the token bucket for a target, creating a full one if needed.
+    fn bucket(&mut self, target: &NotificationTarget) -> &mut TokenBucket {
+        let capacity = self.config.as_ref().map_or(1.0, |config| config.burst.max(1) as f64);
+        match self.buckets.iter().position(|(bucket_target, _)| bucket_target == target) {
+            Some(index) => &mut self.buckets[index].1,
+            None => {
+                let bucket = TokenBucket { tokens: capacity, last_refill: Instant::now(), blocked_until: None };
+                self.buckets.push((target.clone(), bucket));
+                &mut self.buckets.last_mut().expect("bucket was just added").1
+            },
+        }
+    }
+
+    /// Take a token for a target, returning an error with the time a
+    /// token will be available if the target is rate limited.
+    pub fn try_take(&mut self, target: &NotificationTarget) -> Result<(), RateLimited> {
+        let now = Instant::now();
+        let config = self.config.clone();
+        let bucket = self.bucket(target);
+
+        if let Some(blocked_until) = bucket.blocked_until {
+            if blocked_until > now {
+                return Err(RateLimited { until: blocked_until, by_target: true });
+            }
+            bucket.blocked_until = None;
+        }
+
+        let config = match config {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+        let capacity = config.burst.max(1) as f64;
+        let refill_per_second = config.per_minute.max(1) as f64 / 60.0;
+
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill_per_second).min(capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = Duration::from_secs_f64((1.0 - bucket.tokens) / refill_per_second);
+            Err(RateLimited { until: now + wait, by_target: false })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Discord webhook target with a URL.
+    fn target(url: &str) -> NotificationTarget {
+        NotificationTarget::DiscordWebhook { url: url.to_string(), username: None, embeds: false, alarm_mention: None }
+    }
+
+    #[test]
+    fn try_take_allows_a_burst_per_target() {
+        let mut rate_limiter = RateLimiter::new(Some(RateLimitConfig { burst: 2, per_minute: 1 }));
+        let (first, second) = (target("https://example.com/1"), target("https://example.com/2"));
+
+        assert!(rate_limiter.try_take(&first).is_ok());
+        assert!(rate_limiter.try_take(&first).is_ok());
+        let rate_limited = rate_limiter.try_take(&first).unwrap_err();
+        assert!(!rate_limited.by_target);
+        assert!(rate_limited.until > Instant::now() + Duration::from_secs(55));
+
+        assert!(rate_limiter.try_take(&second).is_ok());
+        assert_eq!(rate_limiter.rejected_count(), 0);
+    }
+
+    #[test]
+    fn try_take_holds_back_blocked_targets() {
+        let mut rate_limiter = RateLimiter::new(None);
+        let (first, second) = (target("https://example.com/1"), target("https://example.com/2"));
+        assert!(rate_limiter.try_take(&first).is_ok());
+        assert!(rate_limiter.try_take(&first).is_ok());
+
+        let until = Instant::now() + Duration::from_secs(30);
+        rate_limiter.block(&first, until);
+        let rate_limited = rate_limiter.try_take(&first).unwrap_err();
+        assert!(rate_limited.by_target);
+        assert_eq!(rate_limited.until, until);
+        assert!(rate_limiter.try_take(&second).is_ok());
+        assert_eq!(rate_limiter.rejected_count(), 1);
+
+        // A block that has passed no longer holds back the target.
+        rate_limiter.block(&second, Instant::now());
+        assert!(rate_limiter.try_take(&second).is_ok());
+    }
+}
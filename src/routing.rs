@@ -0,0 +1,336 @@
+use std::{collections::HashMap, sync::RwLock};
+
+use regex::Regex;
+use serde::{Serialize, Deserialize};
+
+use crate::{DeviceId, notification::{DeliveryPolicy, Notification, NotificationTargetConfig, NotificationTargets}, spool::NotificationPriority, status::StatusLevel};
+
+/// How routed targets are combined with the targets a notification
+/// would otherwise be sent to.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum RouteMode {
+    /// Send to the routed targets instead.
+    #[default]
+    Override,
+    /// Send to the routed targets as well, combined targets are sent to
+    /// with fan-out delivery.
+    Extend,
+}
+
+/// Per-device notification targets.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct DeviceNotificationConfig {
+    /// Targets for the device's notifications sent to the status targets.
+    pub status_notification_target: Option<NotificationTargets>,
+
+    /// Targets for the device's notifications sent to the alarm targets.
+    pub alarm_notification_target: Option<NotificationTargets>,
+
+    /// Whether the device's targets replace or extend the global targets.
+    #[serde(default)]
+    pub mode: RouteMode,
+}
+
+/// Notification routing rule, selects targets for notifications that
+/// match every condition that is set.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct NotificationRule {
+    /// Device name to match.
+    pub device: Option<String>,
+
+    /// Status levels to match, defaults to every level.
+    #[serde(default)]
+    pub levels: Vec<StatusLevel>,
+
+    /// Regular expression to search status messages for.
+    pub message: Option<String>,
+
+    /// Targets for matching notifications.
+    pub targets: NotificationTargets,
+
+    /// Whether the rule's targets replace or extend the targets selected
+    /// so far.
+    #[serde(default)]
+    pub mode: RouteMode,
+}
+
+/// Routing table from status levels to the global notification targets
+/// they are sent to, e.g. `{"Info": [], "Critical": ["Alarm", "Status"]}`.
+/// 
+/// Levels that aren't listed use their default targets, see `StatusLevel`.
+/// Levels without any global targets are only sent to the targets of
+/// rules that list the level explicitly.
+#[derive(Clone, Default, Serialize, Deserialize, Debug)]
+#[serde(transparent)]
+pub struct LevelRoutes(HashMap<StatusLevel, Vec<NotificationPriority>>);
+
+impl LevelRoutes {
+    /// Get the notification priorities a status level is sent with.
+    pub fn priorities(&self, level: StatusLevel) -> &[NotificationPriority] {
+        match self.0.get(&level) {
+            Some(priorities) => priorities,
+            None => Self::default_priorities(level),
+        }
+    }
+
+    /// Default notification priorities for a status level.
+    fn default_priorities(level: StatusLevel) -> &'static [NotificationPriority] {
+        match level {
+            StatusLevel::Info => &[],
+            StatusLevel::Status => &[NotificationPriority::Status],
+            StatusLevel::Warning | StatusLevel::Alarm | StatusLevel::Critical => &[NotificationPriority::Alarm],
+        }
+    }
+}
+
+/// Notification rule with its message regex compiled.
+struct CompiledRule {
+    /// Rule configuration.
+    rule: NotificationRule,
+
+    /// Compiled message regex.
+    message: Option<Regex>,
+}
+
+/// Notification router, selects the targets for each notification.
+/// 
+/// A notification is sent with the priorities for its level, for each
+/// priority it starts with the global targets, then its device's targets
+/// are applied, then every matching rule in order. A notification whose
+/// level has no priorities is only sent, as a status notification, to
+/// the rules that list its level explicitly.
+pub struct NotificationRouter {
+    /// Global notification targets for status updates.
+    status_targets: Option<NotificationTargets>,
+
+    /// Global notification targets for alarms.
+    alarm_targets: Option<NotificationTargets>,
+
+    /// Global notification targets for each status level.
+    levels: LevelRoutes,
+
+    /// Routing rules.
+    rules: Vec<CompiledRule>,
+
+    /// Per-device targets.
+    devices: RwLock<HashMap<DeviceId, DeviceNotificationConfig>>,
+}
+
+impl NotificationRouter {
+    /// Create a notification router for the global status and alarm
+    /// targets, rules with an invalid message regex are logged and ignored.
+    pub fn new(
+        status_targets: Option<NotificationTargets>,
+        alarm_targets: Option<NotificationTargets>,
+        levels: LevelRoutes,
+        rules: Vec<NotificationRule>)
+     -> Self
+    {
+        let rules = rules.into_iter()
+            .filter_map(|rule| {
+                let message = match rule.message.as_deref().map(Regex::new).transpose() {
+                    Ok(message) => message,
+                    Err(err) => {
+                        log::error!("Ignoring notification rule with invalid message regex: {}", err);
+                        return None;
+                    },
+                };
+                Some(CompiledRule { rule, message })
+            })
+            .collect();
+
+        Self {
+            status_targets,
+            alarm_targets,
+            levels,
+            rules,
+            devices: Default::default(),
+        }
+    }
+
+    /// Get the global notification targets for a priority, if there are any.
+    pub fn targets(&self, priority: NotificationPriority) -> Option<&NotificationTargets> {
+        let targets = match priority {
+            NotificationPriority::Status => self.status_targets.as_ref(),
+            NotificationPriority::Alarm => self.alarm_targets.as_ref(),
+        };
+        targets.filter(|targets| !targets.is_empty())
+    }
+
+    /// Set the notification targets for a device.
+    pub fn set_device_targets(&self, device_id: DeviceId, config: DeviceNotificationConfig) {
+        self.devices.write().expect("router lock poisoned").insert(device_id, config);
+    }
+
+    /// Select the targets for a notification for each priority it is sent
+    /// with.
+    /// 
+    /// Targets already selected for an earlier priority are left out, so
+    /// a level sent with several priorities reaches each target once.
+    pub fn route(&self, notification: &Notification) -> Vec<(NotificationPriority, NotificationTargets)> {
+        let priorities = self.levels.priorities(notification.level);
+        if priorities.is_empty() {
+            let targets = self.rules.iter()
+                .filter(|compiled| compiled.rule.levels.contains(&notification.level) && Self::matches(compiled, notification))
+                .fold(None, |targets, compiled| Self::apply(targets, compiled.rule.targets.clone(), compiled.rule.mode));
+            return targets.into_iter().map(|targets| (NotificationPriority::Status, targets)).collect();
+        }
+
+        let mut routed: Vec<(NotificationPriority, NotificationTargets)> = vec![];
+        for priority in priorities {
+            let targets = match self.route_priority(notification, *priority, self.targets(*priority)) {
+                Some(targets) => targets,
+                None => continue,
+            };
+            let used: Vec<&NotificationTargetConfig> = routed.iter().flat_map(|(_, targets)| targets.targets()).collect();
+            if let Some(targets) = Self::remove_used(targets, &used) {
+                routed.push((*priority, targets));
+            }
+        }
+        routed
+    }
+
+    /// Select the targets for a notification sent with `priority`, starting
+    /// from `targets`, the global targets for the priority.
+    fn route_priority(
+        &self,
+        notification: &Notification,
+        priority: NotificationPriority,
+        targets: Option<&NotificationTargets>)
+     -> Option<NotificationTargets>
+    {
+        let mut targets = targets.cloned();
+
+        let device_config = notification.device_id
+            .and_then(|device_id| self.devices.read().expect("router lock poisoned").get(&device_id).cloned());
+        if let Some(device_config) = device_config {
+            let device_targets = match priority {
+                NotificationPriority::Status => device_config.status_notification_target,
+                NotificationPriority::Alarm => device_config.alarm_notification_target,
+            };
+            if let Some(device_targets) = device_targets {
+                targets = Self::apply(targets, device_targets, device_config.mode);
+            }
+        }
+
+        for compiled in &self.rules {
+            if Self::matches(compiled, notification) {
+                targets = Self::apply(targets, compiled.rule.targets.clone(), compiled.rule.mode);
+            }
+        }
+
+        targets
+    }
+
+    /// Returns true if a rule matches a notification.
+    fn matches(compiled: &CompiledRule, notification: &Notification) -> bool {
+        let rule = &compiled.rule;
+        rule.device.as_ref().is_none_or(|device| *device == notification.device_name)
+            && (rule.levels.is_empty() || rule.levels.contains(&notification.level))
+            && compiled.message.as_ref().is_none_or(|message| message.is_match(&notification.message))
+    }
+
+    /// Combine routed targets with the targets selected so far.
+    fn apply(targets: Option<NotificationTargets>, routed: NotificationTargets, mode: RouteMode) -> Option<NotificationTargets> {
+        let targets = match (mode, targets) {
+            (RouteMode::Extend, Some(targets)) => targets,
+            _ => return Some(routed),
+        };
+
+        let mut combined = targets.targets().to_vec();
+        for target in routed.targets() {
+            if !combined.contains(target) {
+                combined.push(target.clone());
+            }
+        }
+        Some(NotificationTargets::List { targets: combined, policy: DeliveryPolicy::FanOut })
+    }
+
+    /// Remove the `used` targets from a set of targets, returning None if
+    /// no targets are left.
+    fn remove_used(targets: NotificationTargets, used: &[&NotificationTargetConfig]) -> Option<NotificationTargets> {
+        if !targets.targets().iter().any(|target| used.contains(&target)) {
+            return Some(targets);
+        }
+
+        let policy = targets.policy();
+        let remaining: Vec<NotificationTargetConfig> = targets.targets().iter()
+            .filter(|target| !used.contains(target))
+            .cloned()
+            .collect();
+        match remaining.is_empty() {
+            true => None,
+            false => Some(NotificationTargets::List { targets: remaining, policy }),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Notification targets from JSON.
+    fn targets(targets: serde_json::Value) -> NotificationTargets {
+        serde_json::from_value(targets).unwrap()
+    }
+
+    /// Discord webhook target with a URL on example.com.
+    fn discord(path: &str) -> serde_json::Value {
+        serde_json::json!({ "DiscordWebhook": { "url": format!("https://example.com/{}", path), "username": null } })
+    }
+
+    /// Notification rule from JSON.
+    fn rule(rule: serde_json::Value) -> NotificationRule {
+        serde_json::from_value(rule).unwrap()
+    }
+
+    #[test]
+    fn info_is_only_routed_to_rules_listing_it() {
+        let info_rule = rule(serde_json::json!({ "levels": ["Info"], "targets": discord("info") }));
+        let any_level_rule = rule(serde_json::json!({ "device": "Panel", "targets": discord("panel"), "mode": "Extend" }));
+        let router = NotificationRouter::new(None, None, LevelRoutes::default(), vec![info_rule.clone(), any_level_rule]);
+        let device_id = DeviceId::default();
+        router.set_device_targets(device_id, serde_json::from_value(serde_json::json!({
+            "status_notification_target": discord("device"),
+        })).unwrap());
+
+        let mut info = Notification::system("Monitor started.", StatusLevel::Info);
+        info.device_name = "Panel".to_string();
+        info.device_id = Some(device_id);
+        assert_eq!(router.route(&info), [(NotificationPriority::Status, info_rule.targets)]);
+
+        // Device targets don't receive info unless the level is mapped.
+        let router = NotificationRouter::new(None, None, LevelRoutes::default(), vec![]);
+        router.set_device_targets(device_id, serde_json::from_value(serde_json::json!({
+            "status_notification_target": discord("device"),
+        })).unwrap());
+        assert_eq!(router.route(&info), []);
+
+        let status = Notification::system("Armed.", StatusLevel::Status);
+        assert_eq!(router.route(&status), []);
+    }
+
+    #[test]
+    fn levels_with_several_priorities_reach_each_target_once() {
+        let levels: LevelRoutes = serde_json::from_value(serde_json::json!({ "Critical": ["Alarm", "Status"] })).unwrap();
+        let fire_rule = rule(serde_json::json!({ "message": "fire", "targets": discord("fire") }));
+        let status_targets = targets(discord("status"));
+        let alarm_targets = targets(discord("alarm"));
+        let router = NotificationRouter::new(Some(status_targets.clone()), Some(alarm_targets.clone()), levels.clone(), vec![fire_rule.clone()]);
+
+        let fire = Notification::system("fire", StatusLevel::Critical);
+        assert_eq!(router.route(&fire), [(NotificationPriority::Alarm, fire_rule.targets)]);
+
+        let panic = Notification::system("panic", StatusLevel::Critical);
+        assert_eq!(router.route(&panic), [
+            (NotificationPriority::Alarm, alarm_targets.clone()),
+            (NotificationPriority::Status, status_targets.clone()),
+        ]);
+
+        // Global targets shared by both priorities are sent to once.
+        let both = targets(serde_json::json!({ "targets": [discord("alarm"), discord("status")], "policy": "FanOut" }));
+        let router = NotificationRouter::new(Some(status_targets), Some(both.clone()), levels, vec![]);
+        assert_eq!(router.route(&panic), [(NotificationPriority::Alarm, both)]);
+    }
+}
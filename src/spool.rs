@@ -0,0 +1,480 @@
+use std::{collections::VecDeque, path::PathBuf, time::Duration};
+
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+
+use crate::notification::{Notification, NotificationTargets};
+
+/// Notification spool configuration.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct NotificationSpoolConfig {
+    /// Directory to store undelivered notifications in.
+    /// 
+    /// If not set, undelivered notifications are only kept in memory
+    /// and are lost if Cerberus is restarted.
+    pub directory: Option<String>,
+
+    /// Time in seconds to retry a notification before it is discarded.
+    pub expiry: u64,
+}
+
+/// Notification priority, selects the target a notification is sent to.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum NotificationPriority {
+    /// Status notification.
+    Status,
+    /// High-priority alarm notification.
+    Alarm,
+}
+
+/// Notification waiting to be delivered.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpoolEntry {
+    /// Spool sequence number, also used as the spool file name.
+    pub id: u64,
+
+    /// Notification priority.
+    pub priority: NotificationPriority,
+
+    /// Notification to deliver.
+    pub notification: Notification,
+
+    /// Targets selected by notification routing, or None to use the
+    /// targets configured for the priority.
+    #[serde(default)]
+    pub targets: Option<NotificationTargets>,
+
+    /// Time the notification was queued.
+    pub created: DateTime<Utc>,
+
+    /// Number of failed delivery attempts.
+    pub attempts: u32,
+
+    /// Indices of the targets the notification has already been
+    /// delivered to, for notifications sent to multiple targets.
+    #[serde(default)]
+    pub delivered_targets: Vec<usize>,
+
+    /// Time a notification held during quiet hours is due to be released,
+    /// or None if the notification is queued for delivery.
+    #[serde(default)]
+    pub held_until: Option<DateTime<Utc>>,
+}
+
+/// Notification spool, queues notifications until they are delivered
+/// or expire.
+/// 
+/// Notifications are queued in order for each priority and remember the
+/// targets they have been delivered to, so a retry only goes to the
+/// targets that failed. Notifications that haven't been delivered to
+/// every target before they expire are discarded. Status notifications
+/// held during quiet hours are kept apart until they are released. If a
+/// spool directory is configured, every queued and held notification is
+/// written to disk and reloaded when the spool is opened.
+pub struct NotificationSpool {
+    /// Spool directory, if durable.
+    directory: Option<PathBuf>,
+
+    /// Maximum time to retry a notification.
+    expiry: Duration,
+
+    /// Next spool entry ID.
+    next_id: u64,
+
+    /// Queued alarm notifications.
+    alarms: VecDeque<SpoolEntry>,
+
+    /// Queued status notifications.
+    statuses: VecDeque<SpoolEntry>,
+
+    /// Notifications held during quiet hours.
+    held: Vec<SpoolEntry>,
+}
+
+impl NotificationSpool {
+    /// Default notification expiry if no spool is configured.
+    const DEFAULT_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    /// Delay before the first retry of a failed notification.
+    const INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+
+    /// Maximum delay between retries.
+    const MAX_BACKOFF: Duration = Duration::from_secs(15 * 60);
+
+    /// Create an in-memory notification spool.
+    pub fn in_memory() -> Self {
+        Self {
+            directory: None,
+            expiry: Self::DEFAULT_EXPIRY,
+            next_id: 0,
+            alarms: VecDeque::new(),
+            statuses: VecDeque::new(),
+            held: vec![],
+        }
+    }
+
+    /// Open a notification spool, loading any notifications left in the
+    /// spool directory.
+    pub fn open(config: &NotificationSpoolConfig) -> anyhow::Result<Self> {
+        let mut spool = Self::in_memory();
+        spool.expiry = Duration::from_secs(config.expiry);
+
+        let directory = match &config.directory {
+            Some(directory) => PathBuf::from(directory),
+            None => return Ok(spool),
+        };
+        std::fs::create_dir_all(&directory)?;
+
+        let mut entries = vec![];
+        for dir_entry in std::fs::read_dir(&directory)? {
+            let path = dir_entry?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+
+            let entry = std::fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| Ok(serde_json::from_slice::<SpoolEntry>(&data)?));
+            match entry {
+                Ok(entry) => entries.push(entry),
+                Err(err) => {
+                    log::error!("Discarding unreadable spooled notification '{}': {}", path.to_string_lossy(), err);
+                    let _ = std::fs::remove_file(&path);
+                },
+            }
+        }
+        entries.sort_by_key(|entry| entry.id);
+
+        spool.directory = Some(directory);
+        for entry in entries {
+            spool.next_id = spool.next_id.max(entry.id + 1);
+            if entry.held_until.is_some() {
+                // Held notifications don't expire, they haven't been sent yet.
+                spool.held.push(entry);
+                continue;
+            }
+            if spool.is_expired(&entry) {
+                log::error!("Discarding expired spooled notification '{}'", entry.notification.text);
+                spool.remove_file(&entry);
+                continue;
+            }
+            match entry.priority {
+                NotificationPriority::Alarm => spool.alarms.push_back(entry),
+                NotificationPriority::Status => spool.statuses.push_back(entry),
+            }
+        }
+
+        if !spool.is_empty() {
+            log::info!("Loaded {} spooled notifications", spool.len());
+        }
+
+        Ok(spool)
+    }
+
+    /// Number of queued and held notifications.
+    pub fn len(&self) -> usize {
+        self.alarms.len() + self.statuses.len() + self.held.len()
+    }
+
+    /// Returns true if there are no queued notifications.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns true if queued notifications are kept on disk.
+    pub fn is_durable(&self) -> bool {
+        self.directory.is_some()
+    }
+
+    /// Queue a notification for delivery.
+    pub fn push(&mut self, priority: NotificationPriority, notification: Notification, targets: Option<NotificationTargets>) {
+        let entry = SpoolEntry {
+            id: self.next_id,
+            priority,
+            notification,
+            targets,
+            created: Utc::now(),
+            attempts: 0,
+            delivered_targets: vec![],
+            held_until: None,
+        };
+        self.next_id += 1;
+
+        self.write_file(&entry);
+        self.queue_mut(priority).push_back(entry);
+    }
+
+    /// Hold a status notification during quiet hours until `until`.
+    pub fn hold(&mut self, notification: Notification, targets: Option<NotificationTargets>, until: DateTime<Utc>) {
+        let entry = SpoolEntry {
+            id: self.next_id,
+            priority: NotificationPriority::Status,
+            notification,
+            targets,
+            created: Utc::now(),
+            attempts: 0,
+            delivered_targets: vec![],
+            held_until: Some(until),
+        };
+        self.next_id += 1;
+
+        self.write_file(&entry);
+        self.held.push(entry);
+    }
+
+    /// Earliest time a held notification is due to be released, if any
+    /// notifications are held.
+    pub fn next_release(&self) -> Option<DateTime<Utc>> {
+        self.held.iter().filter_map(|entry| entry.held_until).min()
+    }
+
+    /// Remove and return the held notifications `release` returns true
+    /// for, in the order they were held.
+    /// 
+    /// `release` may update the release time of notifications that are
+    /// kept.
+    pub fn release<F: FnMut(&mut SpoolEntry) -> bool>(&mut self, mut release: F) -> Vec<SpoolEntry> {
+        let mut released = vec![];
+        for mut entry in std::mem::take(&mut self.held) {
+            let held_until = entry.held_until;
+            if release(&mut entry) {
+                self.remove_file(&entry);
+                released.push(entry);
+            } else {
+                if entry.held_until != held_until {
+                    self.write_file(&entry);
+                }
+                self.held.push(entry);
+            }
+        }
+        released
+    }
+
+    /// IDs of the queued notifications of a priority, oldest first.
+    pub fn queued_ids(&self, priority: NotificationPriority) -> Vec<u64> {
+        self.queue(priority).iter().map(|entry| entry.id).collect()
+    }
+
+    /// Get a queued notification.
+    pub fn get(&self, priority: NotificationPriority, id: u64) -> Option<&SpoolEntry> {
+        self.queue(priority).iter().find(|entry| entry.id == id)
+    }
+
+    /// Number of queued notifications of a priority.
+    pub fn queue_len(&self, priority: NotificationPriority) -> usize {
+        self.queue(priority).len()
+    }
+
+    /// Remove a queued notification that has been delivered to all of
+    /// its targets.
+    pub fn delivered(&mut self, priority: NotificationPriority, id: u64) {
+        if let Some(index) = self.position(priority, id) {
+            if let Some(entry) = self.queue_mut(priority).remove(index) {
+                self.remove_file(&entry);
+            }
+        }
+    }
+
+    /// Record the targets a queued notification has been delivered to
+    /// so far, `delivered_targets` is the updated list.
+    pub fn progress(&mut self, priority: NotificationPriority, id: u64, delivered_targets: Vec<usize>) {
+        if let Some(index) = self.position(priority, id) {
+            let entry = &mut self.queue_mut(priority)[index];
+            if entry.delivered_targets != delivered_targets {
+                entry.delivered_targets = delivered_targets;
+                self.write_file(&self.queue(priority)[index]);
+            }
+        }
+    }
+
+    /// Record a failed delivery attempt of a queued notification,
+    /// `delivered_targets` is the updated list of targets it has been
+    /// delivered to.
+    pub fn failed(&mut self, priority: NotificationPriority, id: u64, delivered_targets: Vec<usize>) {
+        if let Some(index) = self.position(priority, id) {
+            let entry = &mut self.queue_mut(priority)[index];
+            entry.attempts += 1;
+            entry.delivered_targets = delivered_targets;
+            self.write_file(&self.queue(priority)[index]);
+        }
+    }
+
+    /// Discard queued notifications that have expired.
+    pub fn expire(&mut self) {
+        for priority in [NotificationPriority::Alarm, NotificationPriority::Status] {
+            let entries: Vec<SpoolEntry> = self.queue_mut(priority).drain(..).collect();
+            for entry in entries {
+                if self.is_expired(&entry) {
+                    log::error!("Notification '{}' could not be delivered after {} attempts and expired", entry.notification.text, entry.attempts);
+                    self.remove_file(&entry);
+                } else {
+                    self.queue_mut(priority).push_back(entry);
+                }
+            }
+        }
+    }
+
+    /// Replace the queued notifications of a priority that `select`
+    /// returns true for with a single notification built from them for
+    /// each set of targets.
+    /// 
+    /// Notifications already delivered to some of their targets are left
+    /// out, merging them would deliver them to those targets again.
+    pub fn coalesce<P: FnMut(&SpoolEntry) -> bool, F: FnMut(Vec<Notification>) -> Notification>(&mut self, priority: NotificationPriority, mut select: P, mut merge: F) {
+        let entries: Vec<SpoolEntry> = self.queue_mut(priority).drain(..).collect();
+        let mut groups: Vec<(Option<NotificationTargets>, Vec<Notification>)> = vec![];
+        for entry in entries {
+            if !entry.delivered_targets.is_empty() || !select(&entry) {
+                self.queue_mut(priority).push_back(entry);
+                continue;
+            }
+            self.remove_file(&entry);
+            match groups.iter_mut().find(|(targets, _)| *targets == entry.targets) {
+                Some((_, notifications)) => notifications.push(entry.notification),
+                None => groups.push((entry.targets, vec![entry.notification])),
+            }
+        }
+
+        for (targets, mut notifications) in groups {
+            let notification = match notifications.len() {
+                1 => notifications.remove(0),
+                _ => merge(notifications),
+            };
+            self.push(priority, notification, targets);
+        }
+    }
+
+    /// Retry delay after a number of failed attempts, exponential with
+    /// random jitter.
+    pub fn backoff(attempts: u32) -> Duration {
+        let backoff = Self::INITIAL_BACKOFF
+            .saturating_mul(1 << attempts.saturating_sub(1).min(16))
+            .min(Self::MAX_BACKOFF);
+        backoff.mul_f64(rand::thread_rng().gen_range(0.5..1.0))
+    }
+
+    /// Returns true if a notification is too old to retry.
+    fn is_expired(&self, entry: &SpoolEntry) -> bool {
+        let age = Utc::now().signed_duration_since(entry.created);
+        age.to_std().is_ok_and(|age| age > self.expiry)
+    }
+
+    /// Index of a queued notification in its priority's queue.
+    fn position(&self, priority: NotificationPriority, id: u64) -> Option<usize> {
+        self.queue(priority).iter().position(|entry| entry.id == id)
+    }
+
+    fn queue(&self, priority: NotificationPriority) -> &VecDeque<SpoolEntry> {
+        match priority {
+            NotificationPriority::Alarm => &self.alarms,
+            NotificationPriority::Status => &self.statuses,
+        }
+    }
+
+    fn queue_mut(&mut self, priority: NotificationPriority) -> &mut VecDeque<SpoolEntry> {
+        match priority {
+            NotificationPriority::Alarm => &mut self.alarms,
+            NotificationPriority::Status => &mut self.statuses,
+        }
+    }
+
+    /// Path of the spool file for an entry.
+    fn file_path(&self, entry: &SpoolEntry) -> Option<PathBuf> {
+        self.directory.as_ref().map(|directory| directory.join(format!("{:020}.json", entry.id)))
+    }
+
+    /// Write an entry to the spool directory, replacing any previous
+    /// version of the entry.
+    fn write_file(&self, entry: &SpoolEntry) {
+        if let Some(path) = self.file_path(entry) {
+            let temp_path = path.with_extension("tmp");
+            let result = serde_json::to_vec(entry)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| Ok(std::fs::write(&temp_path, data)?))
+                .and_then(|_| Ok(std::fs::rename(&temp_path, &path)?));
+            if let Err(err) = result {
+                log::error!("Failed to write notification to spool '{}': {}", path.to_string_lossy(), err);
+            }
+        }
+    }
+
+    /// Remove an entry from the spool directory.
+    fn remove_file(&self, entry: &SpoolEntry) {
+        if let Some(path) = self.file_path(entry) {
+            if let Err(err) = std::fs::remove_file(&path) {
+                log::error!("Failed to remove notification from spool '{}': {}", path.to_string_lossy(), err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::StatusLevel;
+
+    /// Messages of the queued notifications of a priority.
+    fn queued_messages(spool: &NotificationSpool, priority: NotificationPriority) -> Vec<String> {
+        spool.queue(priority).iter().map(|entry| entry.notification.message.clone()).collect()
+    }
+
+    #[test]
+    fn coalesce_merges_queued_notifications() {
+        let mut spool = NotificationSpool::in_memory();
+        for message in ["one", "two", "three"] {
+            spool.push(NotificationPriority::Status, Notification::system(message, StatusLevel::Status), None);
+        }
+        spool.push(NotificationPriority::Alarm, Notification::system("alarm", StatusLevel::Alarm), None);
+
+        spool.coalesce(NotificationPriority::Status, |_| true, |notifications| {
+            let messages: Vec<String> = notifications.into_iter().map(|notification| notification.message).collect();
+            Notification::system(messages.join("+"), StatusLevel::Status)
+        });
+
+        assert_eq!(queued_messages(&spool, NotificationPriority::Status), ["one+two+three"]);
+        assert_eq!(queued_messages(&spool, NotificationPriority::Alarm), ["alarm"]);
+    }
+
+    #[test]
+    fn coalesce_keeps_partially_delivered_notifications() {
+        let mut spool = NotificationSpool::in_memory();
+        for message in ["one", "two", "three"] {
+            spool.push(NotificationPriority::Status, Notification::system(message, StatusLevel::Status), None);
+        }
+        let first = spool.queued_ids(NotificationPriority::Status)[0];
+        spool.progress(NotificationPriority::Status, first, vec![0]);
+
+        spool.coalesce(NotificationPriority::Status, |_| true, |notifications| {
+            let messages: Vec<String> = notifications.into_iter().map(|notification| notification.message).collect();
+            Notification::system(messages.join("+"), StatusLevel::Status)
+        });
+
+        assert_eq!(queued_messages(&spool, NotificationPriority::Status), ["one", "two+three"]);
+        assert_eq!(spool.get(NotificationPriority::Status, first).unwrap().delivered_targets, [0]);
+    }
+
+    #[test]
+    fn held_notifications_survive_reopening() {
+        let directory = std::env::temp_dir().join(format!("cerberus-spool-test-{}", std::process::id()));
+        let config = NotificationSpoolConfig {
+            directory: Some(directory.to_string_lossy().into_owned()),
+            expiry: 60,
+        };
+        let release_time = Utc::now() + chrono::Duration::hours(8);
+
+        let mut spool = NotificationSpool::open(&config).unwrap();
+        spool.hold(Notification::system("held", StatusLevel::Status), None, release_time);
+        drop(spool);
+
+        let mut spool = NotificationSpool::open(&config).unwrap();
+        assert_eq!(spool.next_release(), Some(release_time));
+        assert_eq!(spool.queue_len(NotificationPriority::Status), 0);
+        let released = spool.release(|_| true);
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].notification.message, "held");
+        assert!(spool.is_empty());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,15 +1,23 @@
-use std::{fmt::Display, collections::HashMap, sync::Arc, convert::Infallible, net::SocketAddr};
+use std::{fmt::Display, collections::HashMap, sync::Arc, convert::Infallible, future::Future, net::{IpAddr, SocketAddr}, os::unix::fs::FileTypeExt, path::{Path, PathBuf}, pin::Pin, time::Duration};
 
 use chrono::{DateTime, Utc, Local};
-use tokio::sync::{RwLock, Mutex};
+use serde::{Serialize, Deserialize};
+use futures_util::{future, stream};
+use tokio::{net::UnixListener, sync::{RwLock, Mutex, broadcast}};
 use warp::Filter;
 
-use crate::{notification::NotificationManager, DeviceMonitor, DeviceId, backgroundtask::BackgroundTask};
+use crate::{api, dashboard, debounce::{DebounceConfig, Debouncer}, escalation::{AlarmEscalator, EscalationConfig}, homeassistant::PanelState, mqtt::MqttPublisher, notification::{Notification, NotificationManager}, DeviceId, DeviceKind, backgroundtask::BackgroundTask};
 
-/// Status severity levels for device monitor updates and logging.
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+/// Status severity levels for device monitor updates and logging, in
+/// increasing order of severity.
+/// 
+/// The notification targets for each level can be changed with the
+/// `notification_levels` routing table, the defaults are listed below.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Debug)]
 pub enum StatusLevel {
     /// Low-priority info about a device, not sent to any notification target.
+    /// Notification rules that list `Info` explicitly, or a `notification_levels`
+    /// entry for it, can send it to their targets.
     Info,
     /// Device monitor non-alarm status update, sent to status notification targets.
     Status,
@@ -17,12 +25,139 @@ pub enum StatusLevel {
     Warning,
     /// Device monitor alarm status update, sent to alarm notification targets.
     Alarm,
+    /// Life-safety alarm such as fire or panic, sent to alarm notification targets.
+    Critical,
 }
 
-struct StatusEntry {
-    message: String,
-    timestamp: DateTime<Utc>,
-    level: StatusLevel
+/// Device status update.
+#[derive(Clone, Serialize, Debug)]
+pub struct StatusEntry {
+    /// Event ID, increases with every status update of any device.
+    pub id: u64,
+    /// Status message.
+    pub message: String,
+    /// Time of the update.
+    pub timestamp: DateTime<Utc>,
+    /// Status level.
+    pub level: StatusLevel
+}
+
+/// Status update event published to event stream subscribers.
+#[derive(Clone, Serialize, Debug)]
+pub struct StatusEvent {
+    /// Device ID.
+    pub device_id: DeviceId,
+
+    /// Device name.
+    pub device_name: String,
+
+    /// Status update.
+    #[serde(flatten)]
+    pub status: StatusEntry,
+}
+
+/// Registered device and its latest status.
+#[derive(Clone, Serialize, Debug)]
+pub struct DeviceSummary {
+    /// Device ID.
+    pub id: DeviceId,
+
+    /// Device name.
+    pub name: String,
+
+    /// Kind of device, None for the Cerberus log.
+    #[serde(rename = "type")]
+    pub kind: Option<DeviceKind>,
+
+    /// Latest status message.
+    pub message: Option<String>,
+
+    /// Latest status level.
+    pub level: Option<StatusLevel>,
+
+    /// Time of the latest status update.
+    pub timestamp: Option<DateTime<Utc>>,
+}
+
+/// Status HTTP server configuration.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct StatusServerConfig {
+    /// Whether the status server is started.
+    #[serde(default = "StatusServerConfig::default_enabled")]
+    pub enabled: bool,
+
+    /// Addresses to listen on. IP addresses listen on `port` unless they
+    /// include their own, e.g. `127.0.0.1` or `[::1]:9000`, and Unix domain
+    /// sockets are given as `unix:/run/cerberus/status.sock`. Defaults to
+    /// every interface.
+    #[serde(default = "StatusServerConfig::default_listen")]
+    pub listen: Vec<String>,
+
+    /// TCP port for listen addresses without one.
+    #[serde(default = "StatusServerConfig::default_port")]
+    pub port: u16,
+}
+
+impl StatusServerConfig {
+    /// Default for whether the status server is started.
+    fn default_enabled() -> bool {
+        true
+    }
+
+    /// Default listen addresses.
+    fn default_listen() -> Vec<String> {
+        vec!["::".to_string()]
+    }
+
+    /// Default TCP port.
+    fn default_port() -> u16 {
+        8080
+    }
+
+    /// Parse the listen addresses.
+    fn listen_addresses(&self) -> anyhow::Result<Vec<ListenAddress>> {
+        self.listen.iter()
+            .map(|listen| {
+                if let Some(path) = listen.strip_prefix("unix:") {
+                    return Ok(ListenAddress::Unix(PathBuf::from(path)));
+                }
+                if let Ok(address) = listen.parse::<SocketAddr>() {
+                    return Ok(ListenAddress::Tcp(address));
+                }
+                match listen.parse::<IpAddr>() {
+                    Ok(ip) => Ok(ListenAddress::Tcp(SocketAddr::new(ip, self.port))),
+                    Err(_) => anyhow::bail!("invalid listen address '{}'", listen),
+                }
+            })
+            .collect()
+    }
+}
+
+impl Default for StatusServerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: Self::default_enabled(),
+            listen: Self::default_listen(),
+            port: Self::default_port(),
+        }
+    }
+}
+
+/// Status server listen address.
+enum ListenAddress {
+    /// TCP socket address.
+    Tcp(SocketAddr),
+    /// Unix domain socket path.
+    Unix(PathBuf),
+}
+
+impl Display for ListenAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddress::Tcp(address) => write!(f, "{}", address),
+            ListenAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
 }
 
 /// Internal status manager data.
@@ -31,8 +166,26 @@ struct StatusData {
     /// Device registration.
     devices: Vec<(DeviceId, String)>,
 
+    /// Kind of each registered device monitor.
+    device_kinds: HashMap<DeviceId, DeviceKind>,
+
     /// Status storage.
-    statuses: HashMap<DeviceId, Vec<StatusEntry>>
+    statuses: HashMap<DeviceId, Vec<StatusEntry>>,
+
+    /// ID of the latest status update.
+    last_event_id: u64,
+
+    /// Status event channel, None once the status manager is shut down.
+    events: Option<broadcast::Sender<StatusEvent>>,
+
+    /// MQTT publisher for status updates, if configured.
+    mqtt_publisher: Option<MqttPublisher>,
+
+    /// Alarm escalator, if configured.
+    alarm_escalator: Option<Arc<AlarmEscalator>>,
+
+    /// Status debouncing for devices that have it configured.
+    debouncers: HashMap<DeviceId, Debouncer>,
 }
 
 /// Status manager handle, allows device monitors to update status and
@@ -46,6 +199,10 @@ pub struct StatusManager {
 }
 
 impl StatusManager {
+    /// Number of status events buffered for each event stream subscriber,
+    /// slower subscribers are disconnected and have to resume.
+    const EVENT_CHANNEL_CAPACITY: usize = 256;
+
     /// Create a new status manager.
     pub fn new(notification_manager: NotificationManager) -> Self {
         let manager = Self {
@@ -59,27 +216,40 @@ impl StatusManager {
         {
             let mut status_data = manager.status_data.try_write().expect("status data must be unlocked");
             status_data.devices.push((manager.log_device_id, "Log".to_string()));
+            status_data.events = Some(broadcast::channel(Self::EVENT_CHANNEL_CAPACITY).0);
         }
 
         manager
     }
 
-    /// Register a device monitor with the status manager.
-    pub async fn register_device(&self, device_monitor: &dyn DeviceMonitor) {
+    /// Register a device with the status manager, before its device
+    /// monitor is started.
+    /// 
+    /// `device_key` identifies the device across restarts, see
+    /// `MqttPublisher::announce_device`.
+    pub async fn register_device(&self, device_id: DeviceId, device_kind: DeviceKind, device_name: String, device_key: String) {
         let mut status_data = self.status_data.write().await;
-        status_data.devices.push((device_monitor.id(), "Device".to_string()))
+        if let Some(mqtt_publisher) = &status_data.mqtt_publisher {
+            mqtt_publisher.announce_device(device_id, device_kind, &device_name, &device_key);
+        }
+        status_data.devices.push((device_id, device_name));
+        status_data.device_kinds.insert(device_id, device_kind);
+        self.notification_manager.set_heartbeat_status(Self::heartbeat_summary(&status_data));
     }
 
     /// Submit a status update for a device.
     pub async fn update_status<T: ToString + Display> (&self, device_id: DeviceId, message: T, level: StatusLevel) {
+        let timestamp = Utc::now();
+
+        let mut status_data = self.status_data.write().await;
+        status_data.last_event_id += 1;
         let status_entry = StatusEntry {
+            id: status_data.last_event_id,
             message: message.to_string(),
-            timestamp: Utc::now(),
+            timestamp,
             level,
         };
 
-        let mut status_data = self.status_data.write().await;
-
         // Send status to application log.
         let mut device_name = "Unknwon Device".to_string();
         for (i_id, i_device_name) in &status_data.devices {
@@ -91,23 +261,111 @@ impl StatusManager {
         match level {
             StatusLevel::Info | StatusLevel::Status => log::info!("{}", log_message),
             StatusLevel::Warning => log::warn!("{}", log_message),
-            StatusLevel::Alarm => log::warn!("{}", log_message),
+            StatusLevel::Alarm | StatusLevel::Critical => log::warn!("{}", log_message),
         }
 
         // Add to status list.
+        let previous_status = status_data.statuses.get(&device_id)
+            .and_then(|statuses| statuses.last())
+            .map(|status_entry| (status_entry.message.clone(), status_entry.level));
+        let changed = previous_status.as_ref().is_none_or(|(previous_message, _)| *previous_message != message.to_string());
+        if let Some(events) = &status_data.events {
+            // Sending only fails if there are no subscribers.
+            let _ = events.send(StatusEvent {
+                device_id,
+                device_name: device_name.clone(),
+                status: status_entry.clone(),
+            });
+        }
         if let Some(device_statuses) = status_data.statuses.get_mut(&device_id) {
             device_statuses.push(status_entry);
         } else {
             status_data.statuses.insert(device_id, vec![status_entry]);
         }
+        self.notification_manager.set_heartbeat_status(Self::heartbeat_summary(&status_data));
+
+        // Publish to MQTT.
+        if let Some(mqtt_publisher) = &status_data.mqtt_publisher {
+            mqtt_publisher.publish_status(device_id, &device_name, &message.to_string(), level, timestamp);
+        }
 
         // Send notifications.
-        match level {
-            StatusLevel::Info | StatusLevel::Status => self.notification_manager.send_status(log_message),
-            StatusLevel::Warning | StatusLevel::Alarm => self.notification_manager.send_alarm(log_message),
+        let (previous_message, previous_level) = previous_status.unzip();
+        let mut notification = Notification {
+            text: log_message,
+            message: message.to_string(),
+            level,
+            device_name: device_name.clone(),
+            device_id: Some(device_id),
+            timestamp,
+            digest: vec![],
+            ack_url: None,
+            previous_message,
+            previous_level,
+        };
+
+        // Alarms that escalate get an acknowledgement link, added to the
+        // notification text when it is sent unless the template includes it.
+        if let (StatusLevel::Alarm | StatusLevel::Critical, Some(alarm_escalator)) = (level, &status_data.alarm_escalator) {
+            let token = alarm_escalator.alarm_token(device_id);
+            notification.ack_url = Some(alarm_escalator.ack_url(&token));
+            alarm_escalator.add_alarm(device_id, token, &notification);
+        }
+
+        // Hold status updates until they settle and watch for flapping.
+        let mut flap_changes = None;
+        let notification = match status_data.debouncers.get_mut(&device_id) {
+            Some(debouncer) => {
+                if changed {
+                    flap_changes = debouncer.record_change()
+                        .map(|changes| (changes, debouncer.flap_window()));
+                }
+                match level {
+                    StatusLevel::Info | StatusLevel::Status if !debouncer.delay().is_zero() => {
+                        if let Some(generation) = debouncer.hold(notification) {
+                            self.release_debounced(device_id, generation, debouncer.delay());
+                        }
+                        None
+                    },
+                    _ => {
+                        debouncer.notified(&notification);
+                        Some(notification)
+                    },
+                }
+            },
+            None => Some(notification),
+        };
+        if let Some(notification) = notification {
+            self.notification_manager.send(notification);
+        }
+        drop(status_data);
+
+        if let Some((changes, window)) = flap_changes {
+            let flap_message = format!("{} is flapping, {} state changes in the last {} seconds.", device_name, changes, window);
+            Box::pin(self.log(flap_message, StatusLevel::Warning)).await;
         }
     }
 
+    /// Send a held status notification once the device's status has been
+    /// stable for `delay`.
+    fn release_debounced(&self, device_id: DeviceId, generation: u64, delay: Duration) {
+        let status_manager = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let notification = status_manager.status_data.write().await.debouncers.get_mut(&device_id)
+                .and_then(|debouncer| debouncer.release(generation));
+            if let Some(notification) = notification {
+                status_manager.notification_manager.send(notification);
+            }
+        });
+    }
+
+    /// Debounce status updates and detect flapping for a device.
+    pub async fn set_device_debounce(&self, device_id: DeviceId, config: DebounceConfig) {
+        let mut status_data = self.status_data.write().await;
+        status_data.debouncers.insert(device_id, Debouncer::new(config));
+    }
+
     /// Submit a log message to the status manager.
     /// 
     /// The log message will be forwarded to the configured application
@@ -117,15 +375,194 @@ impl StatusManager {
             StatusLevel::Info => log::info!("{}", message),
             StatusLevel::Status => log::info!("{}", message),
             StatusLevel::Warning => log::warn!("{}", message),
-            StatusLevel::Alarm => log::error!("{}", message),
+            StatusLevel::Alarm | StatusLevel::Critical => log::error!("{}", message),
         }
         self.update_status(self.log_device_id, format!("{}", message), level).await;
     }
 
+    /// Get every registered device with its latest status.
+    pub async fn devices(&self) -> Vec<DeviceSummary> {
+        let status_data = self.status_data.read().await;
+        status_data.devices.iter()
+            .map(|(device_id, device_name)| Self::device_summary(&status_data, *device_id, device_name))
+            .collect()
+    }
+
+    /// Get a registered device with its latest status.
+    pub async fn device(&self, device_id: DeviceId) -> Option<DeviceSummary> {
+        let status_data = self.status_data.read().await;
+        status_data.devices.iter()
+            .find(|(i_id, _)| *i_id == device_id)
+            .map(|(device_id, device_name)| Self::device_summary(&status_data, *device_id, device_name))
+    }
+
+    /// Get the status history of a registered device, oldest first.
+    pub async fn device_history(&self, device_id: DeviceId) -> Option<Vec<StatusEntry>> {
+        let status_data = self.status_data.read().await;
+        if !status_data.devices.iter().any(|(i_id, _)| *i_id == device_id) {
+            return None;
+        }
+        Some(status_data.statuses.get(&device_id).cloned().unwrap_or_default())
+    }
+
+    /// Subscribe to status update events, returning the events after
+    /// `last_event_id`, if set, and a receiver for new events. Returns None
+    /// if the status manager is shut down.
+    /// 
+    /// The receiver is closed when the status manager shuts down.
+    pub async fn subscribe(&self, last_event_id: Option<u64>) -> Option<(Vec<StatusEvent>, broadcast::Receiver<StatusEvent>)> {
+        let status_data = self.status_data.read().await;
+        let receiver = status_data.events.as_ref()?.subscribe();
+
+        let mut missed = vec![];
+        if let Some(last_event_id) = last_event_id {
+            for (device_id, device_name) in &status_data.devices {
+                let statuses = status_data.statuses.get(device_id).map(Vec::as_slice).unwrap_or_default();
+                let first_missed = statuses.partition_point(|status_entry| status_entry.id <= last_event_id);
+                missed.extend(statuses[first_missed..].iter().map(|status_entry| StatusEvent {
+                    device_id: *device_id,
+                    device_name: device_name.clone(),
+                    status: status_entry.clone(),
+                }));
+            }
+            missed.sort_by_key(|event| event.status.id);
+        }
+
+        Some((missed, receiver))
+    }
+
+    /// Build the summary of a device.
+    fn device_summary(status_data: &StatusData, device_id: DeviceId, device_name: &str) -> DeviceSummary {
+        let latest_status = status_data.statuses.get(&device_id).and_then(|statuses| statuses.last());
+        DeviceSummary {
+            id: device_id,
+            name: device_name.to_string(),
+            kind: status_data.device_kinds.get(&device_id).copied(),
+            message: latest_status.map(|status_entry| status_entry.message.clone()),
+            level: latest_status.map(|status_entry| status_entry.level),
+            timestamp: latest_status.map(|status_entry| status_entry.timestamp),
+        }
+    }
+
+    /// Build a heartbeat message summarizing the latest status of every
+    /// registered device.
+    fn heartbeat_summary(status_data: &StatusData) -> String {
+        let mut summary = "Cerberus heartbeat, monitor is running.".to_string();
+
+        for (device_id, device_name) in &status_data.devices {
+            let latest_status = status_data.statuses.get(device_id).and_then(|statuses| statuses.last());
+            match latest_status {
+                Some(status_entry) => {
+                    let ts = status_entry.timestamp.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S");
+                    summary.push_str(&format!("\n[{}, {:?}] {} ({})", device_name, status_entry.level, status_entry.message, ts));
+                },
+                None => summary.push_str(&format!("\n[{}] No status entries.", device_name)),
+            }
+        }
+
+        summary
+    }
+
+    /// Submit an alarm panel state update for a device.
+    pub async fn update_panel_state(&self, device_id: DeviceId, panel_state: PanelState) {
+        let status_data = self.status_data.read().await;
+        if let Some(mqtt_publisher) = &status_data.mqtt_publisher {
+            mqtt_publisher.publish_panel_state(device_id, panel_state);
+        }
+    }
+
+    /// Publish status updates to MQTT.
+    pub async fn set_mqtt_publisher(&self, mqtt_publisher: MqttPublisher) {
+        let mut status_data = self.status_data.write().await;
+        status_data.mqtt_publisher = Some(mqtt_publisher);
+    }
+
+    /// Escalate alarms that are not acknowledged.
+    pub async fn start_alarm_escalation(&self, config: EscalationConfig) {
+        let alarm_escalator = AlarmEscalator::start(config, self.clone(), self.notification_manager.clone());
+        let mut status_data = self.status_data.write().await;
+        status_data.alarm_escalator = Some(alarm_escalator);
+    }
+
+    /// Show a page asking to confirm an alarm acknowledgement.
+    /// 
+    /// Acknowledging takes a POST from the page's form, so link previews
+    /// that fetch the acknowledgement link don't acknowledge the alarm.
+    async fn confirm_acknowledge_alarm(&self, token: String) -> Result<impl warp::Reply, Infallible> {
+        let alarm_escalator = self.status_data.read().await.alarm_escalator.clone();
+        let alarm = alarm_escalator.and_then(|alarm_escalator| alarm_escalator.pending_alarm(&token));
+        let alarm = match alarm {
+            Some(alarm) => alarm,
+            None => return Ok(Self::ack_page("Unknown or already acknowledged alarm.", false, warp::http::StatusCode::NOT_FOUND)),
+        };
+
+        let message = format!("Alarm '{}' on {}.", alarm.notification.message, alarm.notification.device_name);
+        Ok(Self::ack_page(&message, true, warp::http::StatusCode::OK))
+    }
+
+    /// Acknowledge an alarm, stopping its escalation.
+    async fn acknowledge_alarm(&self, token: String, remote: Option<SocketAddr>) -> Result<impl warp::Reply, Infallible> {
+        let alarm_escalator = self.status_data.read().await.alarm_escalator.clone();
+        let alarm = alarm_escalator.and_then(|alarm_escalator| alarm_escalator.acknowledge(&token));
+        let alarm = match alarm {
+            Some(alarm) => alarm,
+            None => return Ok(Self::ack_page("Unknown or already acknowledged alarm.", false, warp::http::StatusCode::NOT_FOUND)),
+        };
+
+        let remote = remote.map(|remote| format!(" from {}", remote.ip())).unwrap_or_default();
+        self.log(format!("Alarm '{}' on {} acknowledged{}.", alarm.notification.message, alarm.notification.device_name, remote), StatusLevel::Status).await;
+        Ok(Self::ack_page("Alarm acknowledged.", false, warp::http::StatusCode::OK))
+    }
+
+    /// Alarm acknowledgement page showing a message, with a button to
+    /// acknowledge the alarm if `confirm` is set.
+    fn ack_page(message: &str, confirm: bool, status: warp::http::StatusCode) -> impl warp::Reply {
+        let message = message.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;");
+        let form = match confirm {
+            true => "<form method=\"post\"><button type=\"submit\">Acknowledge alarm</button></form>\n",
+            false => "",
+        };
+        let html = format!(
+            "<!DOCTYPE html>\n<html lang=\"en\">\n<head><meta charset=\"utf-8\"><meta name=\"viewport\" content=\"width=device-width, initial-scale=1\"><title>Cerberus</title></head>\n<body>\n<p>{}</p>\n{}</body>\n</html>\n",
+            message, form);
+        warp::reply::with_status(warp::reply::html(html), status)
+    }
+
+    /// Stop the status HTTP server, alarm escalator and MQTT publisher.
+    pub async fn shutdown(&self) {
+        // Close event streams so the server can stop.
+        self.status_data.write().await.events = None;
+
+        // Send debounced notifications that are still waiting to settle.
+        let held: Vec<Notification> = self.status_data.write().await.debouncers.values_mut()
+            .filter_map(|debouncer| debouncer.flush())
+            .collect();
+        for notification in held {
+            self.notification_manager.send(notification);
+        }
+
+        if let Some(mut server_task) = self.server_task.lock().await.take() {
+            let _ = server_task.finish().await;
+        }
+
+        let alarm_escalator = self.status_data.write().await.alarm_escalator.take();
+        if let Some(alarm_escalator) = alarm_escalator {
+            alarm_escalator.shutdown().await;
+        }
+
+        let mqtt_publisher = self.status_data.write().await.mqtt_publisher.take();
+        if let Some(mqtt_publisher) = mqtt_publisher {
+            mqtt_publisher.shutdown().await;
+        }
+    }
+
     /// Start the status HTTP server on a background thread.
-    pub async fn serve(&self) -> anyhow::Result<()> {
+    /// 
+    /// Returns an error if any listen address can't be bound, the server
+    /// still listens on the addresses that could be.
+    pub async fn serve(&self, config: &StatusServerConfig) -> anyhow::Result<()> {
         let mut server_task = self.server_task.lock().await;
-        if let Some(_) = *server_task {
+        if server_task.is_some() {
             anyhow::bail!("status server already started");
         }
 
@@ -137,37 +574,100 @@ impl StatusManager {
                 self_inner2.status_txt().await
             }
         });
+        let self_inner1 = self.clone();
+        let confirm_ack = warp::get().and(warp::path!("ack" / String)).and_then(move |token| {
+            let self_inner2 = self_inner1.clone();
+            async move {
+                self_inner2.confirm_acknowledge_alarm(token).await
+            }
+        });
+        let self_inner1 = self.clone();
+        let ack = warp::post().and(warp::path!("ack" / String)).and(warp::addr::remote()).and_then(move |token, remote| {
+            let self_inner2 = self_inner1.clone();
+            async move {
+                self_inner2.acknowledge_alarm(token, remote).await
+            }
+        });
+
+        let routes = test.or(confirm_ack).or(ack).or(api::routes(self.clone())).or(dashboard::route());
+        let listen_addresses = config.listen_addresses()?;
 
         // Start warp server in a background task.
+        let mut bind_errors = vec![];
         let task_result = BackgroundTask::try_spawn(|shutdown_token| {
-            // Wrap the shutdown token in a future for bind_with_graceful_shutdown.
-            let shutdown_future = async move {
-                shutdown_token.cancelled().await;
-            };
-
-            let bind_result = warp::serve(test)
-                .try_bind_with_graceful_shutdown("[::]:8080".parse::<SocketAddr>().unwrap(), shutdown_future);
-
-            // If we were able to bind to the port, start the server.
-            match bind_result {
-                Ok(server) => Ok(async move { server.1.await } ),
-                Err(err) => Err(anyhow::anyhow!(err)),
+            let mut servers: Vec<Pin<Box<dyn Future<Output = ()> + Send>>> = vec![];
+            for listen_address in &listen_addresses {
+                // Wrap the shutdown token in a future for graceful shutdown.
+                let shutdown_token = shutdown_token.clone();
+                let shutdown_future = async move {
+                    shutdown_token.cancelled().await;
+                };
+
+                match listen_address {
+                    ListenAddress::Tcp(address) => {
+                        match warp::serve(routes.clone()).try_bind_with_graceful_shutdown(*address, shutdown_future) {
+                            Ok((_, server)) => servers.push(Box::pin(server)),
+                            Err(err) => bind_errors.push(format!("{}: {}", listen_address, err)),
+                        }
+                    },
+                    ListenAddress::Unix(path) => {
+                        match Self::bind_unix(path) {
+                            Ok(listener) => {
+                                let incoming = stream::unfold(listener, |listener| async move {
+                                    let stream = listener.accept().await.map(|(stream, _)| stream);
+                                    Some((stream, listener))
+                                });
+                                let path = path.clone();
+                                let server = warp::serve(routes.clone()).serve_incoming_with_graceful_shutdown(incoming, shutdown_future);
+                                servers.push(Box::pin(async move {
+                                    server.await;
+                                    let _ = std::fs::remove_file(path);
+                                }));
+                            },
+                            Err(err) => bind_errors.push(format!("{}: {}", listen_address, err)),
+                        }
+                    },
+                }
+            }
+
+            // If we were able to bind to any address, start the server.
+            match servers.is_empty() {
+                false => Ok(async move {
+                    future::join_all(servers).await;
+                }),
+                true => Err(()),
             }
         });
 
-        *server_task = Some(task_result?);
+        if let Ok(task) = task_result {
+            *server_task = Some(task);
+        }
+        if !bind_errors.is_empty() {
+            anyhow::bail!("unable to listen on {}", bind_errors.join(", "));
+        }
 
         Ok(())
     }
 
+    /// Bind a Unix domain socket, replacing a stale socket file left by a
+    /// previous run.
+    fn bind_unix(path: &Path) -> std::io::Result<UnixListener> {
+        if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            std::fs::remove_file(path)?;
+        }
+        UnixListener::bind(path)
+    }
+
     async fn status_txt(&self) -> Result<String, Infallible> {
         let mut status_text = String::new();
         let status_data = self.status_data.read().await;
 
         status_text.push_str("Cerberus Status:\n");
+        status_text.push_str(&format!("Pending notifications: {}\n", self.notification_manager.pending_notifications()));
+        status_text.push_str(&format!("Rate limited notification attempts: {}\n", self.notification_manager.rate_limited_attempts()));
 
         for (device_id, device_name) in &status_data.devices {
-            status_text.push_str("\n");
+            status_text.push('\n');
             status_text.push_str(&format!("{}\n", device_name));
             if let Some(statuses) = status_data.statuses.get(device_id) {
                 for status_entry in statuses {
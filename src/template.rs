@@ -0,0 +1,107 @@
+use chrono::Local;
+use lazy_static::lazy_static;
+
+use crate::notification::Notification;
+
+lazy_static! {
+    /// Host name of the machine Cerberus is running on.
+    static ref HOSTNAME: String = {
+        hostname::get().map(|hostname| hostname.to_string_lossy().into_owned()).unwrap_or_default()
+    };
+}
+
+/// Render a notification template.
+/// 
+/// Supported placeholders are `{text}` (the formatted notification
+/// text), `{message}`, `{level}`, `{device_name}`, `{device_id}`,
+/// `{timestamp}` (RFC 3339, UTC), `{local_timestamp}`,
+/// `{utc_timestamp}`, `{hostname}`, `{previous_message}` and
+/// `{previous_level}` (the device's previous status, empty if there is
+/// none) and `{ack_url}` (the alarm acknowledgement link, empty if there
+/// is none). Each value is passed through `escape` before it is
+/// inserted, any other text is copied unchanged.
+pub fn render(template: &str, notification: &Notification, escape: fn(&str) -> String) -> String {
+    let device_id = notification.device_id.map(|device_id| device_id.to_string()).unwrap_or_default();
+    let previous_level = notification.previous_level.map(|level| format!("{:?}", level)).unwrap_or_default();
+    let values = [
+        ("{text}", notification.text.clone()),
+        ("{message}", notification.message.clone()),
+        ("{level}", format!("{:?}", notification.level)),
+        ("{device_name}", notification.device_name.clone()),
+        ("{device_id}", device_id),
+        ("{timestamp}", notification.timestamp.to_rfc3339()),
+        ("{local_timestamp}", notification.timestamp.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S %Z").to_string()),
+        ("{utc_timestamp}", notification.timestamp.format("%Y-%m-%d %H:%M:%S UTC").to_string()),
+        ("{hostname}", HOSTNAME.clone()),
+        ("{previous_message}", notification.previous_message.clone().unwrap_or_default()),
+        ("{previous_level}", previous_level),
+        ("{ack_url}", notification.ack_url.clone().unwrap_or_default()),
+    ];
+
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    'outer: while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+        for (placeholder, value) in &values {
+            if rest.starts_with(placeholder) {
+                rendered.push_str(&escape(value));
+                rest = &rest[placeholder.len()..];
+                continue 'outer;
+            }
+        }
+        rendered.push('{');
+        rest = &rest[1..];
+    }
+    rendered.push_str(rest);
+
+    rendered
+}
+
+/// Insert values unchanged.
+pub fn escape_none(value: &str) -> String {
+    value.to_string()
+}
+
+/// Escape values for use inside a JSON string.
+pub fn escape_json(value: &str) -> String {
+    let quoted = serde_json::Value::String(value.to_string()).to_string();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::StatusLevel;
+
+    /// Alarm notification with a message that needs escaping.
+    fn notification() -> Notification {
+        let mut notification = Notification::system("Zone \"Front door\"\nopen", StatusLevel::Alarm);
+        notification.text = "Cerberus: Zone \"Front door\"\nopen".to_string();
+        notification
+    }
+
+    #[test]
+    fn unknown_placeholders_are_copied_unchanged() {
+        let notification = notification();
+        assert_eq!(render("{level} {unknown} {{level}} {", &notification, escape_none), "Alarm {unknown} {Alarm} {");
+        assert_eq!(render("{previous_level}{ack_url}", &notification, escape_none), "");
+    }
+
+    #[test]
+    fn json_values_escape_quotes_and_newlines() {
+        let notification = notification();
+        let body = render(r#"{"content": "{message}", "level": "{level}"}"#, &notification, escape_json);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["content"], "Zone \"Front door\"\nopen");
+        assert_eq!(body["level"], "Alarm");
+        assert_eq!(escape_json("a\\b\t\"c\""), r#"a\\b\t\"c\""#);
+    }
+
+    #[test]
+    fn unescaped_values_are_inserted_unchanged() {
+        let notification = notification();
+        assert_eq!(render("{device_name}: {message}", &notification, escape_none), "Cerberus: Zone \"Front door\"\nopen");
+        assert_eq!(render("{text}", &notification, escape_none), notification.text);
+    }
+}